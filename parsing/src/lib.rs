@@ -25,9 +25,50 @@ pub fn weechat_arg_strip(str: &str) -> String {
     str.trim().replace(' ', "_")
 }
 
+/// The pictographs and symbols a unicode emoji can start with, as a regex character class body
+///
+/// An emoji can be followed by more of these, and by skin tones, variation selectors, zero width
+/// joiners and tags.
+macro_rules! emoji_start {
+    () => {
+        concat!(
+            r"\x{00A9}\x{00AE}\x{203C}\x{2049}\x{2122}\x{2139}\x{2190}-\x{21FF}\x{2300}-\x{23FF}",
+            r"\x{24C2}\x{25A0}-\x{25FF}\x{2600}-\x{27BF}\x{2900}-\x{297F}\x{2B00}-\x{2BFF}",
+            r"\x{3030}\x{303D}\x{3297}\x{3299}\x{1F000}-\x{1FAFF}",
+        )
+    };
+}
+
 lazy_static! {
     static ref LINE_SUB_REGEX: Regex =
         Regex::new(r"^(\d)?s/(.*?(?<!\\))/(.*?(?<!\\))(?:/|$)(\w+)?").unwrap();
+    // `[\s\S]` instead of `(?s)`, which oniguruma's default syntax rejects, so multi-line
+    // replies are kept whole
+    static ref REPLY_REGEX: Regex = Regex::new(r"\A(\d)?\^\s+([\s\S]+)").unwrap();
+    static ref MESSAGE_LINK_REGEX: Regex = Regex::new(
+        r"^(?:https?://)?(?:(?:ptb|canary)\.)?discord(?:app)?\.com/channels/(@me|\d+)/(\d+)/(\d+)"
+    )
+    .unwrap();
+    static ref REACTION_REGEX: Regex = Regex::new(concat!(
+        r"\A(\d)?([+-])(:\w+:|[",
+        emoji_start!(),
+        "][",
+        emoji_start!(),
+        r"\x{200D}\x{20E3}\x{FE0F}\x{E0020}-\x{E007F}]*)\s*\z"
+    ))
+    .unwrap();
+    static ref MENTION_REGEX: Regex =
+        Regex::new(r"^<(?:t:(-?\d+)(?::([tTdDfFR]))?|#(\d+)|@&(\d+)|/([^:<>]+):(\d+))>").unwrap();
+    static ref HEADER_REGEX: Regex = Regex::new(r"^(#{1,3}) +(.+)$").unwrap();
+    static ref SUBTEXT_REGEX: Regex = Regex::new(r"^-# +(.+)$").unwrap();
+    static ref LIST_ITEM_REGEX: Regex = Regex::new(r"^( *)(?:([-*])|(\d{1,9})\.) +(.+)$").unwrap();
+    static ref MASKED_LINK_REGEX: Regex =
+        Regex::new(r"^\[([^\[\]\n]+)\]\(<?(https?://[^\s()<>]+)>?\)").unwrap();
+    static ref IRC_COLOR_REGEX: Regex = Regex::new(r"^\x03(?:\d{1,2}(?:,\d{1,2})?)?").unwrap();
+    static ref UNESCAPED_REGEX: Regex = Regex::new(
+        r"^(?:<(?:@[!&]?\d+|#\d+|a?:\w+:\d+|t:-?\d+(?::\w)?|/[^<>\s]+:\d+)>|https?://\S+)"
+    )
+    .unwrap();
 }
 
 #[derive(Debug)]
@@ -61,3 +102,564 @@ pub fn parse_line_edit(input: &str) -> Option<LineEdit> {
         })
     }
 }
+
+#[derive(Debug, PartialEq)]
+pub struct Reply<'a> {
+    pub line: usize,
+    pub text: &'a str,
+}
+
+/// Parse a reply in the form `[n]^ text`, replying to the nth most recent message
+pub fn parse_reply(input: &str) -> Option<Reply> {
+    let caps = REPLY_REGEX.captures(input)?;
+
+    let line = caps.at(1).and_then(|l| l.parse().ok()).unwrap_or(1);
+    let text = caps.at(2)?;
+
+    Some(Reply { line, text })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReactionEdit<'a> {
+    Add { line: usize, emoji: &'a str },
+    Remove { line: usize, emoji: &'a str },
+}
+
+/// Parse a reaction in the form `[n]+:emoji:` or `[n]-:emoji:`, reacting to the nth most recent
+/// message
+pub fn parse_reaction(input: &str) -> Option<ReactionEdit> {
+    let caps = REACTION_REGEX.captures(input)?;
+
+    let line = caps.at(1).and_then(|l| l.parse().ok()).unwrap_or(1);
+    let emoji = caps.at(3)?;
+
+    match caps.at(2)? {
+        "+" => Some(ReactionEdit::Add { line, emoji }),
+        _ => Some(ReactionEdit::Remove { line, emoji }),
+    }
+}
+
+/// Parse a message that is entirely in italics, which is how `/me` actions are sent, returning
+/// the text of the action
+pub fn parse_action(input: &str) -> Option<&str> {
+    let input = input.trim();
+    let delimiter = input.chars().next().filter(|c| *c == '_' || *c == '*')?;
+    if input.len() < 3 || !input.ends_with(delimiter) {
+        return None;
+    }
+
+    // Anything else than a single italic span, like `**bold**` or `_one_ _two_`, is not an action
+    let text = &input[1..input.len() - 1];
+    if text.contains(delimiter) || text.contains('\n') || text.trim() != text {
+        return None;
+    }
+    Some(text)
+}
+
+#[derive(Debug)]
+pub struct MessageLink {
+    pub guild: Option<u64>,
+    pub channel: u64,
+    pub message: u64,
+}
+
+/// Parse a link to a message, as copied from the Discord client
+pub fn parse_message_link(input: &str) -> Option<MessageLink> {
+    let caps = MESSAGE_LINK_REGEX.captures(input.trim())?;
+
+    Some(MessageLink {
+        guild: caps.at(1).and_then(|g| g.parse().ok()),
+        channel: caps.at(2)?.parse().ok()?,
+        message: caps.at(3)?.parse().ok()?,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Mention<'a> {
+    /// A unix timestamp, with one of Discord's style flags (`t`, `T`, `d`, `D`, `f`, `F` or `R`)
+    Timestamp {
+        unix: i64,
+        style: char,
+    },
+    Channel(u64),
+    Role(u64),
+    SlashCommand {
+        name: &'a str,
+        id: u64,
+    },
+}
+
+fn parse_mention(input: &str) -> Option<(Mention, usize)> {
+    let caps = MENTION_REGEX.captures(input)?;
+    let len = caps.at(0)?.len();
+
+    let mention = if let Some(unix) = caps.at(1) {
+        Mention::Timestamp {
+            unix: unix.parse().ok()?,
+            style: caps.at(2).and_then(|s| s.chars().next()).unwrap_or('f'),
+        }
+    } else if let Some(channel) = caps.at(3) {
+        Mention::Channel(channel.parse().ok()?)
+    } else if let Some(role) = caps.at(4) {
+        Mention::Role(role.parse().ok()?)
+    } else {
+        Mention::SlashCommand {
+            name: caps.at(5)?,
+            id: caps.at(6)?.parse().ok()?,
+        }
+    };
+    Some((mention, len))
+}
+
+/// Replace timestamps and channel, role and slash command mentions using `render`, mentions it
+/// returns `None` for are left as they are
+pub fn replace_mentions(input: &str, mut render: impl FnMut(Mention) -> Option<String>) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        match parse_mention(rest).and_then(|(mention, len)| Some((render(mention)?, len))) {
+            Some((rendered, len)) => {
+                out.push_str(&rendered);
+                rest = &rest[len..];
+            },
+            None => {
+                out.push('<');
+                rest = &rest[1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ListMarker {
+    Bullet,
+    Number(u64),
+}
+
+/// A line level construct, the text of each block still has to be parsed with `parse_markdown`
+#[derive(Debug, PartialEq)]
+pub enum Block<'a> {
+    /// A `#`, `##` or `###` header
+    Header { level: usize, text: &'a str },
+    /// Small text, starting with `-#`
+    Subtext(&'a str),
+    /// A bulleted or numbered list item, nested by its indentation
+    ListItem {
+        indent: usize,
+        marker: ListMarker,
+        text: &'a str,
+    },
+    /// Any other lines, including quotes and code blocks
+    Text(&'a str),
+}
+
+fn parse_block_line(line: &str) -> Option<Block> {
+    if let Some(caps) = SUBTEXT_REGEX.captures(line) {
+        return Some(Block::Subtext(caps.at(1)?));
+    }
+    if let Some(caps) = HEADER_REGEX.captures(line) {
+        return Some(Block::Header {
+            level: caps.at(1)?.len(),
+            text: caps.at(2)?,
+        });
+    }
+    let caps = LIST_ITEM_REGEX.captures(line)?;
+    let marker = match caps.at(3) {
+        Some(number) => ListMarker::Number(number.parse().ok()?),
+        None => ListMarker::Bullet,
+    };
+    Some(Block::ListItem {
+        // Discord nests list items by at least two spaces per level
+        indent: caps.at(1)?.len() / 2,
+        marker,
+        text: caps.at(4)?,
+    })
+}
+
+/// Split a message into headers, subtext, list items and runs of other lines
+///
+/// Lines inside code blocks are never treated as headers or lists.
+pub fn parse_blocks(input: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut in_code_block = false;
+    // The byte range of the current run of text lines
+    let mut text: Option<(usize, usize)> = None;
+    let mut offset = 0;
+
+    for line in input.split('\n') {
+        let start = offset;
+        offset += line.len() + 1;
+
+        let block = if in_code_block {
+            None
+        } else {
+            parse_block_line(line)
+        };
+        if line.matches("```").count() % 2 == 1 {
+            in_code_block = !in_code_block;
+        }
+
+        match block {
+            Some(block) => {
+                if let Some((start, end)) = text.take() {
+                    blocks.push(Block::Text(&input[start..end]));
+                }
+                blocks.push(block);
+            },
+            None => {
+                let end = start + line.len();
+                text = Some(text.map_or((start, end), |(start, _)| (start, end)));
+            },
+        }
+    }
+    if let Some((start, end)) = text {
+        blocks.push(Block::Text(&input[start..end]));
+    }
+    blocks
+}
+
+/// Replace `[text](url)` masked links using `render`, links inside code are left as they are
+pub fn replace_masked_links(input: &str, mut render: impl FnMut(&str, &str) -> String) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find(|c| c == '[' || c == '`') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with('`') {
+            // Copy code verbatim, up to the closing run of the same amount of backticks
+            let ticks = rest.len() - rest.trim_start_matches('`').len();
+            let fence = &rest[..ticks];
+            let len = rest[ticks..]
+                .find(fence)
+                .map_or(ticks, |end| ticks + end + ticks);
+            out.push_str(&rest[..len]);
+            rest = &rest[len..];
+            continue;
+        }
+
+        match MASKED_LINK_REGEX.captures(rest) {
+            Some(caps) => {
+                let len = caps.at(0).map_or(1, str::len);
+                out.push_str(&render(
+                    caps.at(1).unwrap_or_default(),
+                    caps.at(2).unwrap_or_default(),
+                ));
+                rest = &rest[len..];
+            },
+            None => {
+                out.push('[');
+                rest = &rest[1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Convert the IRC formatting codes that can be typed in weechat to Discord markdown
+///
+/// If `escape` is set, markdown typed in the text itself is escaped so that it is sent as typed.
+/// Mentions, emojis and links are never escaped.
+pub fn irc_to_markdown(input: &str, escape: bool) -> String {
+    const STYLES: &[(char, &str)] = &[
+        ('\x02', "**"),
+        ('\x1D', "*"),
+        ('\x1F', "__"),
+        ('\x1E', "~~"),
+        ('\x11', "`"),
+    ];
+
+    let mut out = String::with_capacity(input.len());
+    // The markers of the styles that are currently open, innermost last
+    let mut open: Vec<&str> = Vec::new();
+    let mut line_start = true;
+    let mut rest = input;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some(&(_, marker)) = STYLES.iter().find(|(code, _)| *code == c) {
+            match open.iter().rposition(|open| *open == marker) {
+                Some(index) => {
+                    // Markdown styles can't overlap, so the styles opened after this one are
+                    // closed with it and opened again
+                    let closed: Vec<_> = open.drain(index..).collect();
+                    closed.iter().rev().for_each(|marker| out.push_str(marker));
+                    for &marker in &closed[1..] {
+                        out.push_str(marker);
+                        open.push(marker);
+                    }
+                },
+                None => {
+                    out.push_str(marker);
+                    open.push(marker);
+                },
+            }
+            rest = &rest[1..];
+            continue;
+        }
+        match c {
+            // Reset
+            '\x0F' => {
+                open.drain(..).rev().for_each(|marker| out.push_str(marker));
+                rest = &rest[1..];
+                continue;
+            },
+            // Colors and reverse video have no markdown equivalent
+            '\x03' => {
+                let len = IRC_COLOR_REGEX
+                    .captures(rest)
+                    .and_then(|caps| caps.at(0).map(str::len))
+                    .unwrap_or(1);
+                rest = &rest[len..];
+                continue;
+            },
+            '\x16' => {
+                rest = &rest[1..];
+                continue;
+            },
+            _ => {},
+        }
+
+        // Nothing can be escaped inside of code
+        if escape && !open.contains(&"`") {
+            if let Some(unescaped) = UNESCAPED_REGEX.captures(rest).and_then(|caps| caps.at(0)) {
+                out.push_str(unescaped);
+                rest = &rest[unescaped.len()..];
+                line_start = false;
+                continue;
+            }
+            if "\\*_~|`[".contains(c) || (line_start && "#>-".contains(c)) {
+                out.push('\\');
+            }
+        }
+        out.push(c);
+        line_start = c == '\n';
+        rest = &rest[c.len_utf8()..];
+    }
+    open.drain(..).rev().for_each(|marker| out.push_str(marker));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn irc_styles_are_converted() {
+        assert_eq!(irc_to_markdown("\x02bold\x02 text", false), "**bold** text");
+        assert_eq!(
+            irc_to_markdown("\x1Fline\x1F \x11code\x11", false),
+            "__line__ `code`"
+        );
+        // Styles that are still open at the end are closed
+        assert_eq!(irc_to_markdown("\x02bold", false), "**bold**");
+    }
+
+    #[test]
+    fn irc_styles_are_nested() {
+        assert_eq!(
+            irc_to_markdown("\x02bold \x1Dboth\x1D\x02", false),
+            "**bold *both***"
+        );
+        // Closing the outer style closes the inner one, which is opened again
+        assert_eq!(irc_to_markdown("\x02a\x1Db\x02c\x1D", false), "**a*b****c*");
+    }
+
+    #[test]
+    fn irc_reset_closes_all_styles() {
+        assert_eq!(irc_to_markdown("\x02\x1Dab\x0Fc", false), "***ab***c");
+        assert_eq!(
+            irc_to_markdown("\x0304,05red\x03 text\x0F", false),
+            "red text"
+        );
+    }
+
+    #[test]
+    fn irc_markdown_is_escaped() {
+        assert_eq!(
+            irc_to_markdown("*a* <@123> _b_", true),
+            "\\*a\\* <@123> \\_b\\_"
+        );
+        assert_eq!(irc_to_markdown("# not a header", true), "\\# not a header");
+        assert_eq!(irc_to_markdown("\x11*code*\x11", true), "`*code*`");
+    }
+
+    #[test]
+    fn replies_are_parsed() {
+        assert_eq!(
+            parse_reply("^ sounds good"),
+            Some(Reply {
+                line: 1,
+                text: "sounds good"
+            })
+        );
+        assert_eq!(
+            parse_reply("2^ first\nsecond"),
+            Some(Reply {
+                line: 2,
+                text: "first\nsecond"
+            })
+        );
+        assert_eq!(parse_reply("^no space"), None);
+        assert_eq!(parse_reply("x^ text"), None);
+    }
+
+    #[test]
+    fn reactions_are_parsed() {
+        assert_eq!(
+            parse_reaction("+:thumbsup:"),
+            Some(ReactionEdit::Add {
+                line: 1,
+                emoji: ":thumbsup:"
+            })
+        );
+        assert_eq!(
+            parse_reaction("2-👍"),
+            Some(ReactionEdit::Remove {
+                line: 2,
+                emoji: "👍"
+            })
+        );
+        assert_eq!(
+            parse_reaction("+👍🏽"),
+            Some(ReactionEdit::Add {
+                line: 1,
+                emoji: "👍🏽"
+            })
+        );
+        // Only emojis are reactions, anything else is sent as a message
+        assert_eq!(parse_reaction("-é"), None);
+        assert_eq!(parse_reaction("+日本語"), None);
+        assert_eq!(parse_reaction("+1 for this"), None);
+    }
+
+    #[test]
+    fn actions_are_parsed() {
+        assert_eq!(parse_action("_waves_"), Some("waves"));
+        assert_eq!(parse_action(" *waves* "), Some("waves"));
+        assert_eq!(parse_action("**bold**"), None);
+        assert_eq!(parse_action("_one_ _two_"), None);
+        assert_eq!(parse_action("_ spaced_"), None);
+        assert_eq!(parse_action("__"), None);
+    }
+
+    #[test]
+    fn masked_links_are_replaced() {
+        let render = |text: &str, url: &str| format!("{} <{}>", text, url);
+        assert_eq!(
+            replace_masked_links("see [the docs](https://example.com/docs)", render),
+            "see the docs <https://example.com/docs>"
+        );
+        assert_eq!(
+            replace_masked_links("[text](<https://example.com>)", render),
+            "text <https://example.com>"
+        );
+        // Links inside code and brackets that are not links are left as they are
+        assert_eq!(
+            replace_masked_links("`[x](https://example.com)` [not a link]", render),
+            "`[x](https://example.com)` [not a link]"
+        );
+    }
+
+    #[test]
+    fn mentions_are_replaced() {
+        let rendered = replace_mentions("<#1> <@&2> <t:3:R> <t:-4> </ping:5>", |mention| {
+            Some(match mention {
+                Mention::Channel(id) => format!("#channel{}", id),
+                Mention::Role(id) => format!("@role{}", id),
+                Mention::Timestamp { unix, style } => format!("{}{}", unix, style),
+                Mention::SlashCommand { name, id } => format!("/{}{}", name, id),
+            })
+        });
+        assert_eq!(rendered, "#channel1 @role2 3R -4f /ping5");
+    }
+
+    #[test]
+    fn unknown_mentions_are_kept() {
+        let rendered = replace_mentions("<#1> <#2> <@&3>", |mention| match mention {
+            Mention::Channel(1) => Some("#general".to_owned()),
+            _ => None,
+        });
+        assert_eq!(rendered, "#general <#2> <@&3>");
+
+        // User mentions are rendered by serenity, and anything else is not a mention
+        let rendered = replace_mentions("<@4> <@!4> <#x> <", |_| Some(String::new()));
+        assert_eq!(rendered, "<@4> <@!4> <#x> <");
+    }
+
+    #[test]
+    fn headers_and_subtext_are_parsed() {
+        assert_eq!(
+            parse_blocks("# title\ntext\nmore\n-# small\n### sub"),
+            vec![
+                Block::Header {
+                    level: 1,
+                    text: "title"
+                },
+                Block::Text("text\nmore"),
+                Block::Subtext("small"),
+                Block::Header {
+                    level: 3,
+                    text: "sub"
+                },
+            ]
+        );
+        assert_eq!(parse_blocks("#### four"), vec![Block::Text("#### four")]);
+        assert_eq!(parse_blocks("#nospace"), vec![Block::Text("#nospace")]);
+    }
+
+    #[test]
+    fn list_items_are_nested() {
+        assert_eq!(
+            parse_blocks("- one\n  * two\n    3. three\n10. ten"),
+            vec![
+                Block::ListItem {
+                    indent: 0,
+                    marker: ListMarker::Bullet,
+                    text: "one"
+                },
+                Block::ListItem {
+                    indent: 1,
+                    marker: ListMarker::Bullet,
+                    text: "two"
+                },
+                Block::ListItem {
+                    indent: 2,
+                    marker: ListMarker::Number(3),
+                    text: "three"
+                },
+                Block::ListItem {
+                    indent: 0,
+                    marker: ListMarker::Number(10),
+                    text: "ten"
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_and_code_blocks_are_text() {
+        assert_eq!(
+            parse_blocks("> quote\n> > nested\n> # not a header\n- item"),
+            vec![
+                Block::Text("> quote\n> > nested\n> # not a header"),
+                Block::ListItem {
+                    indent: 0,
+                    marker: ListMarker::Bullet,
+                    text: "item"
+                },
+            ]
+        );
+        assert_eq!(
+            parse_blocks("```\n# code\n- code\n```"),
+            vec![Block::Text("```\n# code\n- code\n```")]
+        );
+    }
+}
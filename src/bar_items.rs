@@ -1,13 +1,21 @@
-use crate::utils::BufferExt;
+use crate::{
+    discord::{self, connection, mentions},
+    utils::BufferExt,
+};
 use serenity::model::id::{ChannelId, GuildId};
-use std::borrow::Cow;
-use weechat::{bar::BarItem, ConfigOption, Weechat};
+use std::{borrow::Cow, time::Duration};
+use weechat::{bar::BarItem, ConfigOption, TimerHook, Weechat};
+
+/// How often the latency in `discord_status` is refreshed
+const STATUS_REFRESH: Duration = Duration::from_secs(30);
 
 pub struct BarHandles {
     _guild_name: BarItem<()>,
     _channel_name: BarItem<()>,
     _full_name: BarItem<()>,
     _typing_indicator: BarItem<()>,
+    _status: BarItem<()>,
+    _status_timer: TimerHook<()>,
 }
 
 pub fn init(weechat: &Weechat) -> BarHandles {
@@ -72,12 +80,47 @@ pub fn init(weechat: &Weechat) -> BarHandles {
         None,
     );
 
+    let _status = weechat.new_bar_item(
+        "discord_status",
+        |_, _, buffer| connection_status(&buffer.account()),
+        None,
+    );
+    let _status_timer = weechat.hook_timer(
+        STATUS_REFRESH,
+        0,
+        0,
+        |_, _, _| crate::on_main(|weecord| weecord.update_bar_item("discord_status")),
+        None,
+    );
+
     BarHandles {
         _guild_name,
         _channel_name,
         _full_name,
         _typing_indicator,
+        _status,
+        _status_timer,
+    }
+}
+
+/// The connection state of an account, with its latency and unread mentions
+fn connection_status(account: &str) -> String {
+    let state = connection::state(account);
+    let mut status = if account == discord::DEFAULT_ACCOUNT {
+        state.to_string()
+    } else {
+        format!("{}: {}", account, state)
+    };
+    if state.is_ready() {
+        if let Some(latency) = discord::latency(account) {
+            status.push_str(&format!(" {}ms", latency.as_millis()));
+        }
+    }
+    let mentions = mentions::count(account);
+    if mentions > 0 {
+        status.push_str(&format!(" @{}", mentions));
     }
+    status
 }
 
 fn terse_typing_list(channel_id: ChannelId, guild_id: Option<GuildId>, max_names: usize) -> String {
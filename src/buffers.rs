@@ -1,4 +1,11 @@
 use crate::{
+    config::Config,
+    discord::{
+        forums::{self, Forum},
+        search::{SearchResults, SearchScope},
+        threads::{self, Thread},
+    },
+    message_store::MessageStore,
     on_main,
     sync::on_main_blocking,
     utils,
@@ -23,19 +30,26 @@ use weechat::{buffer::HotlistPriority, Buffer, ConfigOption, NickArgs, Weechat};
 const OFFLINE_GROUP_NAME: &str = "99999|Offline";
 const ONLINE_GROUP_NAME: &str = "99998|Online";
 const BOT_GROUP_NAME: &str = "99997|Bot";
+/// The maximum amount of messages Discord will return in a single request
+const MAX_FETCH_COUNT: u64 = 100;
 
-pub fn init(weechat: &Weechat) -> BufferManager {
-    BufferManager::new(Weechat::from_ptr(weechat.as_ptr()))
+pub fn init(weechat: &Weechat, config: &Config) -> BufferManager {
+    let message_store = if config.message_log.value() {
+        MessageStore::new(weechat).map(Arc::new)
+    } else {
+        None
+    };
+    BufferManager::new(Weechat::from_ptr(weechat.as_ptr()), message_store)
 }
 
-pub fn create_buffers(ready_data: &Ready) {
-    let ctx = match crate::discord::get_ctx() {
+pub fn create_buffers(account: &str, ready_data: &Ready) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
     let current_user = ctx.cache.read().user.clone();
 
-    let guilds = match current_user.guilds(ctx) {
+    let guilds = match current_user.guilds(&*ctx) {
         Ok(guilds) => guilds,
         Err(e) => {
             crate::plugin_print(&format!("Error getting user guilds: {:?}", e));
@@ -70,28 +84,29 @@ pub fn create_buffers(ready_data: &Ready) {
         } else {
             guild_muted = false;
         }
-        create_guild_buffer(guild.id, &guild.name);
+        create_guild_buffer(account, guild.id, &guild.name);
 
         // TODO: Colors?
-        let nick = if let Ok(current_member) = guild.id.member(ctx, current_user.id) {
+        let nick = if let Ok(current_member) = guild.id.member(&*ctx, current_user.id) {
             format!("@{}", current_member.display_name())
         } else {
             format!("@{}", current_user.name)
         };
-        let channels = guild.id.channels(ctx).expect("Unable to fetch channels");
+        let channels = guild.id.channels(&*ctx).expect("Unable to fetch channels");
         let mut channels = channels.values().collect::<Vec<_>>();
         channels.sort_by_key(|g| g.position);
         for channel in channels {
             let is_muted =
                 guild_muted || channel_muted.get(&channel.id).cloned().unwrap_or_default();
-            create_buffer_from_channel(&ctx.cache, &guild.name, &channel, &nick, is_muted);
+            create_buffer_from_channel(&ctx.cache, account, &guild.name, &channel, &nick, is_muted);
         }
+        create_forum_buffers(account, guild.id, &guild.name, &nick, None);
     }
 }
 
 // TODO: Merge these functions
-pub fn create_autojoin_buffers(_ready: &Ready) {
-    let ctx = match crate::discord::get_ctx() {
+pub fn create_autojoin_buffers(account: &str, _ready: &Ready) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
@@ -113,7 +128,7 @@ pub fn create_autojoin_buffers(_ready: &Ready) {
                 None => continue,
             };
             let last_msg = match channel
-                .to_channel_cached(ctx)
+                .to_channel_cached(&*ctx)
                 .and_then(|c| c.last_message())
             {
                 Some(msg) => msg,
@@ -127,7 +142,7 @@ pub fn create_autojoin_buffers(_ready: &Ready) {
     }
 
     for channel_id in cache.all_private_channels() {
-        let channel = match channel_id.to_channel_cached(&ctx) {
+        let channel = match channel_id.to_channel_cached(&*ctx) {
             Some(ch) => ch,
             None => continue,
         };
@@ -141,13 +156,34 @@ pub fn create_autojoin_buffers(_ready: &Ready) {
         }
     }
 
+    // Forums are not cached, so they are fetched for autojoined guilds, and for autojoined
+    // channels that are not known
+    let nick = format!("@{}", current_user.name);
+    for item in &autojoin_items {
+        let (guild_id, forum) = match item {
+            utils::GuildOrChannel::Guild(guild_id) => (*guild_id, None),
+            utils::GuildOrChannel::Channel(Some(guild_id), channel) => {
+                if cache.channels.contains_key(channel) {
+                    continue;
+                }
+                (*guild_id, Some(channel.0.to_string()))
+            },
+            utils::GuildOrChannel::Channel(None, _) => continue,
+        };
+        if let Some(guild) = cache.guilds.get(&guild_id) {
+            let guild_name = guild.read().name.clone();
+            create_forum_buffers(account, guild_id, &guild_name, &nick, forum.as_deref());
+        }
+    }
+
     // flatten guilds into channels
     let autojoin_channels = utils::flatten_guilds(&ctx, &autojoin_items);
 
-    create_buffers_from_flat_items(&ctx, &current_user, &autojoin_channels);
+    create_buffers_from_flat_items(account, &ctx, &current_user, &autojoin_channels);
 }
 
 pub fn create_buffers_from_flat_items(
+    account: &str,
     ctx: &Context,
     current_user: &CurrentUser,
     channels: &IndexMap<Option<GuildId>, Vec<ChannelId>>,
@@ -170,14 +206,14 @@ pub fn create_buffers_from_flat_items(
                 };
                 let nick = &nick;
 
-                create_guild_buffer(guild.id, &guild.name);
+                create_guild_buffer(account, guild.id, &guild.name);
 
                 parking_lot::RwLockReadGuard::unlock_fair(guild);
 
                 for channel in channels {
                     // TODO: Muting
                     let () = on_main_blocking(move |_| {
-                        let ctx = match crate::discord::get_ctx() {
+                        let ctx = match crate::discord::get_ctx(account) {
                             Some(ctx) => ctx,
                             _ => return,
                         };
@@ -198,6 +234,7 @@ pub fn create_buffers_from_flat_items(
 
                         create_buffer_from_channel(
                             &ctx.cache,
+                            account,
                             &guild.name,
                             &channel.read(),
                             &nick,
@@ -207,15 +244,12 @@ pub fn create_buffers_from_flat_items(
                 }
             },
             (None, channels) => {
-                let ctx = match crate::discord::get_ctx() {
-                    Some(ctx) => ctx,
-                    _ => return,
-                };
                 let cache = ctx.cache.read();
                 let nick = cache.user.name.to_string();
 
                 for channel_id in channels {
                     let nick = format!("@{}", nick);
+                    let account = account.to_owned();
                     let channel = if let Ok(channel) = channel_id.to_channel(ctx) {
                         channel
                     } else {
@@ -225,19 +259,21 @@ pub fn create_buffers_from_flat_items(
 
                     match channel {
                         channel @ Channel::Private(_) => on_main(move |weecord| {
-                            let ctx = match crate::discord::get_ctx() {
+                            let ctx = match crate::discord::get_ctx(&account) {
                                 Some(ctx) => ctx,
                                 _ => return,
                             };
-                            create_buffer_from_dm(&ctx.cache, weecord, channel, &nick, false);
+                            create_buffer_from_dm(
+                                &ctx.cache, weecord, &account, channel, &nick, false,
+                            );
                         }),
 
                         channel @ Channel::Group(_) => on_main(move |weecord| {
-                            let ctx = match crate::discord::get_ctx() {
+                            let ctx = match crate::discord::get_ctx(&account) {
                                 Some(ctx) => ctx,
                                 _ => return,
                             };
-                            create_buffer_from_group(&ctx.cache, weecord, channel, &nick);
+                            create_buffer_from_group(&ctx.cache, weecord, &account, channel, &nick);
                         }),
                         _ => unreachable!(),
                     }
@@ -258,11 +294,12 @@ fn user_online(cache: &Cache, user_id: UserId) -> bool {
     }
 }
 
-pub fn create_guild_buffer(id: GuildId, name: &str) {
-    let guild_name_id = utils::buffer_id_for_guild(id);
+pub fn create_guild_buffer(account: &str, id: GuildId, name: &str) {
+    let guild_name_id = utils::buffer_id_for_guild(account, id);
     let () = on_main_blocking(move |weecord| {
         let buffer = weecord.buffer_manager.get_or_create_buffer(&guild_name_id);
 
+        buffer.set_account(account);
         buffer.set_localvar("guild_name", name);
         buffer.set_localvar("server", name);
         buffer.set_short_name(name);
@@ -273,6 +310,7 @@ pub fn create_guild_buffer(id: GuildId, name: &str) {
 
 pub fn create_buffer_from_channel(
     cache: &CacheRwLock,
+    account: &str,
     guild_name: &str,
     channel: &GuildChannel,
     nick: &str,
@@ -293,7 +331,7 @@ pub fn create_buffer_from_channel(
         ChannelType::__Nonexhaustive => unreachable!(),
     };
 
-    let name_id = utils::buffer_id_for_channel(Some(channel.guild_id), channel.id);
+    let name_id = utils::buffer_id_for_channel(account, Some(channel.guild_id), channel.id);
     let has_unread = cache
         .read()
         .read_state
@@ -306,6 +344,7 @@ pub fn create_buffer_from_channel(
 
         buffer.set_short_name(&channel.name);
 
+        buffer.set_account(account);
         buffer.set_localvar("channelid", &channel.id.0.to_string());
         buffer.set_localvar("guildid", &channel.guild_id.0.to_string());
         buffer.set_localvar("channel", &channel.name);
@@ -335,10 +374,211 @@ pub fn create_buffer_from_channel(
     });
 }
 
+/// Fetch the forums of a guild in the background and create their index buffers
+///
+/// Serenity does not know about forums, so every guild has to be asked for them separately.
+/// `name` only creates the buffer of the forum with that name or id.
+pub fn create_forum_buffers(
+    account: &str,
+    guild: GuildId,
+    guild_name: &str,
+    nick: &str,
+    name: Option<&str>,
+) {
+    let account = account.to_owned();
+    let guild_name = guild_name.to_owned();
+    let nick = nick.to_owned();
+    let name = name.map(str::to_lowercase);
+
+    std::thread::spawn(move || {
+        let mut found = false;
+        for forum in forums::fetch_guild_forums(&account, guild) {
+            if let Some(name) = &name {
+                if parsing::weechat_arg_strip(&forum.name).to_lowercase() != *name
+                    && forum.id.0.to_string() != *name
+                {
+                    continue;
+                }
+            }
+            if !found {
+                create_guild_buffer(&account, guild, &guild_name);
+                found = true;
+            }
+            create_forum_buffer(&account, &guild_name, &forum, &nick);
+        }
+        if name.is_some() && !found {
+            crate::plugin_print("Couldn't find channel");
+        }
+    });
+}
+
+/// Create an index buffer for a forum, listing its posts
+pub fn create_forum_buffer(account: &str, guild_name: &str, forum: &Forum, nick: &str) {
+    let name_id = utils::buffer_id_for_channel(account, Some(forum.guild_id), forum.id);
+
+    let () = on_main_blocking(|weecord| {
+        let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
+        utils::set_forum_buffer(&buffer);
+
+        buffer.set_short_name(&forum.name);
+
+        buffer.set_account(account);
+        buffer.set_localvar("channelid", &forum.id.0.to_string());
+        buffer.set_localvar("guildid", &forum.guild_id.0.to_string());
+        buffer.set_localvar("channel", &forum.name);
+        buffer.set_localvar("guild_name", guild_name);
+        buffer.set_localvar("server", guild_name);
+        buffer.set_localvar("type", "channel");
+        buffer.set_localvar("nick", &nick);
+
+        let title = match &forum.topic {
+            Some(topic) if !topic.is_empty() => format!("{} | {}", forum.name, topic),
+            _ => format!("{} | forum", forum.name),
+        };
+        buffer.set_title(&title);
+    });
+}
+
+/// List the posts of a forum in its index buffer
+pub fn load_forum_index(buffer: &MessageManager) {
+    let account = buffer.account();
+    let forum = match buffer
+        .channel_id()
+        .and_then(|forum| forums::get(&account, forum))
+    {
+        Some(forum) => forum,
+        None => return,
+    };
+    buffer.set_history_loaded();
+    let buffer_name = buffer.get_name().to_string();
+
+    std::thread::spawn(move || {
+        let posts = forums::posts(&account, &forum);
+        on_main(move |weecord| {
+            let buffer = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            buffer.clear();
+
+            let posts = match posts {
+                Ok(posts) => posts,
+                Err(e) => {
+                    buffer.print(&format!(
+                        "{}\tUnable to load forum posts: {}",
+                        weecord.get_prefix("network"),
+                        e
+                    ));
+                    return;
+                },
+            };
+
+            buffer.print(&format!(
+                "{}\t{} posts, enter a post number to open it or create one with /discord post <title> | <message>",
+                weecord.get_prefix("network"),
+                posts.len()
+            ));
+            for (index, post) in posts.iter().enumerate() {
+                let tags = forum.tag_names(post);
+                let tags = if tags.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", tags.join(", "))
+                };
+                let last_activity = post
+                    .last_message_id
+                    .unwrap_or(MessageId(post.id.0))
+                    .created_at()
+                    .format("%Y-%m-%d %H:%M");
+                buffer.print(&format!(
+                    "{}[{}]{}\t{}{}{} {}({} replies, last active {}){}",
+                    weecord.color("gray"),
+                    index + 1,
+                    weecord.color("reset"),
+                    post.name,
+                    weecord.color("cyan"),
+                    tags,
+                    weecord.color("gray"),
+                    post.message_count,
+                    last_activity,
+                    weecord.color("reset"),
+                ));
+            }
+        });
+    });
+}
+
+/// Open the nth post listed in a forum index buffer
+pub fn open_forum_post(weecord: &Discord, buffer: &MessageManager, n: usize) {
+    let post = match buffer
+        .channel_id()
+        .and_then(|forum| forums::nth_post(&buffer.account(), forum, n))
+    {
+        Some(post) => post,
+        None => {
+            buffer.print(&format!(
+                "{}\tThere is no post {}",
+                weecord.get_prefix("network"),
+                n
+            ));
+            return;
+        },
+    };
+
+    if let Some(target) = open_channel_buffer(weecord, &buffer.account(), post.id) {
+        target.switch_to();
+    }
+}
+
+/// Create a buffer for a thread, named under its parent channel
+pub fn create_buffer_from_thread(
+    cache: &CacheRwLock,
+    weecord: &Discord,
+    account: &str,
+    thread: &Thread,
+    nick: &str,
+) -> Arc<MessageManager> {
+    let parent_name = thread
+        .parent_id
+        .to_channel_cached(cache)
+        .map(|parent| parent.name())
+        .or_else(|| forums::get(account, thread.parent_id).map(|forum| forum.name))
+        .unwrap_or_else(|| thread.parent_id.0.to_string());
+    let guild_name = thread
+        .guild_id
+        .to_guild_cached(cache)
+        .map(|guild| guild.read().name.clone())
+        .unwrap_or_default();
+
+    let name_id = utils::buffer_id_for_channel(account, Some(thread.guild_id), thread.id);
+    let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
+
+    buffer.set_short_name(&format!("{}/{}", parent_name, thread.name));
+
+    buffer.set_account(account);
+    buffer.set_localvar("channelid", &thread.id.0.to_string());
+    buffer.set_localvar("guildid", &thread.guild_id.0.to_string());
+    buffer.set_localvar("parent_channel", &thread.parent_id.0.to_string());
+    buffer.set_localvar("channel", &thread.name);
+    buffer.set_localvar("guild_name", &guild_name);
+    buffer.set_localvar("server", &guild_name);
+    buffer.set_localvar("type", "channel");
+    buffer.set_localvar("nick", &nick);
+
+    let mut title = format!("{} | thread in #{}", thread.name, parent_name);
+    if thread.archived {
+        title += " (archived)";
+    }
+    buffer.set_title(&title);
+
+    buffer
+}
+
 // TODO: Reduce code duplication
 pub fn create_buffer_from_dm(
     cache: &CacheRwLock,
     weecord: &crate::Discord,
+    account: &str,
     channel: Channel,
     nick: &str,
     switch_to: bool,
@@ -349,10 +589,11 @@ pub fn create_buffer_from_dm(
     };
     let channel = channel.read();
 
-    let name_id = utils::buffer_id_for_channel(None, channel.id);
+    let name_id = utils::buffer_id_for_channel(account, None, channel.id);
     let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
 
     buffer.set_short_name(&channel.name());
+    buffer.set_account(account);
     buffer.set_localvar("channelid", &channel.id.0.to_string());
     buffer.set_localvar("nick", &nick);
 
@@ -379,6 +620,7 @@ pub fn create_buffer_from_dm(
 pub fn create_buffer_from_group(
     cache: &CacheRwLock,
     weecord: &Discord,
+    account: &str,
     channel: Channel,
     nick: &str,
 ) {
@@ -398,11 +640,12 @@ pub fn create_buffer_from_group(
             .join(", ")
     );
 
-    let name_id = utils::buffer_id_for_channel(None, channel.channel_id);
+    let name_id = utils::buffer_id_for_channel(account, None, channel.channel_id);
 
     let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
 
     buffer.set_short_name(&channel.name());
+    buffer.set_account(account);
     buffer.set_localvar("channelid", &channel.channel_id.0.to_string());
     buffer.set_localvar("nick", &nick);
     buffer.set_title(&title);
@@ -419,11 +662,12 @@ pub fn create_buffer_from_group(
     }
 }
 
-pub fn create_pins_buffer(weecord: &Discord, channel: &Channel) {
-    let buffer_name = format!("Pins.{}", channel.id().0);
+pub fn create_pins_buffer(weecord: &Discord, account: &str, channel: &Channel) {
+    let buffer_name = format!("{}Pins.{}", utils::account_prefix(account), channel.id().0);
 
     let buffer = weecord.buffer_manager.get_or_create_buffer(&buffer_name);
     buffer.switch_to();
+    buffer.set_account(account);
 
     buffer.set_title(&format!("Pinned messages in #{}", channel.name()));
     buffer.set_full_name(&format!("Pinned messages in ${}", channel.name()));
@@ -431,6 +675,258 @@ pub fn create_pins_buffer(weecord: &Discord, channel: &Channel) {
     utils::set_pins_for_channel(&buffer, channel.id());
 }
 
+pub fn create_search_buffer(
+    weecord: &Discord,
+    account: &str,
+    scope: SearchScope,
+    title: &str,
+    results: &SearchResults,
+) {
+    let ctx = match crate::discord::get_ctx(account) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    let scope_id = match scope {
+        SearchScope::Guild(guild) => guild.0,
+        SearchScope::Channel(channel) => channel.0,
+    };
+    let buffer_name = format!("{}Search.{}", utils::account_prefix(account), scope_id);
+
+    let buffer = weecord.buffer_manager.get_or_create_buffer(&buffer_name);
+    buffer.set_account(account);
+    utils::set_search_buffer(&buffer);
+    buffer.set_history_loaded();
+    buffer.clear();
+    buffer.switch_to();
+
+    buffer.set_title(title);
+    buffer.set_short_name("search");
+
+    let weechat = buffer.get_weechat();
+    buffer.print(&format!(
+        "{}\tShowing {} of {} results, enter a result number to jump to it",
+        weechat.get_prefix("network"),
+        results.messages.len(),
+        results.total
+    ));
+    for (index, msg) in results.messages.iter().enumerate() {
+        buffer.add_search_result(&ctx.cache, msg, index + 1);
+    }
+}
+
+/// Jump to the context of the nth result in a search buffer
+pub fn open_search_result(weecord: &Discord, buffer: &MessageManager, n: usize) {
+    let msg = match n.checked_sub(1).and_then(|index| buffer.message_at(index)) {
+        Some(msg) => msg,
+        None => {
+            buffer.print(&format!(
+                "{}\tThere is no result {}",
+                weecord.get_prefix("network"),
+                n
+            ));
+            return;
+        },
+    };
+
+    if let Some(target) = open_channel_buffer(weecord, &buffer.account(), msg.channel_id) {
+        load_page(&target, Page::Around(msg.id));
+        target.switch_to();
+    }
+}
+
+/// Get the buffer for a channel, creating it if it is not open yet
+pub fn open_channel_buffer(
+    weecord: &Discord,
+    account: &str,
+    channel_id: ChannelId,
+) -> Option<Arc<MessageManager>> {
+    let ctx = crate::discord::get_ctx(account)?;
+    let nick = format!("@{}", ctx.cache.read().user.name);
+
+    let channel = match channel_id.to_channel_cached(&ctx.cache) {
+        Some(channel) => channel,
+        None => {
+            let thread = threads::get(account, channel_id)?;
+            return Some(create_buffer_from_thread(
+                &ctx.cache, weecord, account, &thread, &nick,
+            ));
+        },
+    };
+
+    let guild_id = match &channel {
+        Channel::Guild(guild_channel) => {
+            let guild_channel = guild_channel.read();
+            if let Some(guild) = guild_channel.guild_id.to_guild_cached(&ctx.cache) {
+                let guild_name = guild.read().name.clone();
+                create_guild_buffer(account, guild_channel.guild_id, &guild_name);
+                create_buffer_from_channel(
+                    &ctx.cache,
+                    account,
+                    &guild_name,
+                    &guild_channel,
+                    &nick,
+                    false,
+                );
+            }
+            Some(guild_channel.guild_id)
+        },
+        Channel::Private(_) => {
+            create_buffer_from_dm(&ctx.cache, weecord, account, channel.clone(), &nick, false);
+            None
+        },
+        Channel::Group(_) => {
+            create_buffer_from_group(&ctx.cache, weecord, account, channel.clone(), &nick);
+            None
+        },
+        _ => return None,
+    };
+
+    weecord
+        .buffer_manager
+        .get_buffer(&utils::buffer_id_for_channel(account, guild_id, channel_id))
+}
+
+/// A page of messages to load into a buffer, relative to a message
+#[derive(Debug, Clone, Copy)]
+pub enum Page {
+    Around(MessageId),
+    Before(MessageId),
+    After(MessageId),
+}
+
+/// Replace the contents of a buffer with a page of older messages, detaching it from new messages
+/// until the newest page is reached again
+pub fn load_page(buffer: &MessageManager, page: Page) {
+    const PAGE_SIZE: u64 = 50;
+
+    let channel = match buffer.channel_id() {
+        Some(channel) => channel,
+        None => return,
+    };
+    // Prevent switching to the buffer from loading the latest history over the page
+    buffer.set_history_loaded();
+    let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
+
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+
+        let mut msgs = match channel.messages(&*ctx, |retriever| match page {
+            Page::Around(id) => retriever.around(id).limit(PAGE_SIZE),
+            Page::Before(id) => retriever.before(id).limit(PAGE_SIZE),
+            Page::After(id) => retriever.after(id).limit(PAGE_SIZE),
+        }) {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                crate::plugin_print(&format!("Unable to load messages: {}", e));
+                return;
+            },
+        };
+        if msgs.is_empty() {
+            crate::plugin_print("No more messages");
+            return;
+        }
+        msgs.sort_by_key(|msg| msg.id);
+        let latest = match page {
+            Page::After(_) => (msgs.len() as u64) < PAGE_SIZE,
+            _ => false,
+        };
+
+        on_main(move |weecord| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let buf = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                Some(buf) => buf,
+                None => return,
+            };
+
+            buf.clear();
+            buf.set_detached(!latest);
+            buf.set_marked_message(match page {
+                Page::Around(id) => Some(id),
+                _ => None,
+            });
+            for msg in &msgs {
+                buf.add_message(&ctx.cache, msg, false);
+            }
+            if !latest {
+                buf.print(&format!(
+                    "{}\tViewing older messages, use /discord goto prev|next to page or \
+                     /discord goto latest to return",
+                    weecord.get_prefix("network")
+                ));
+            }
+        });
+    });
+}
+
+/// Fetch messages older than the oldest message in a buffer and add them to the start of it,
+/// keeping the previously oldest message at the top of the window
+pub fn load_older_messages(buffer: &MessageManager, count: u64) {
+    let channel = match buffer.channel_id() {
+        Some(channel) => channel,
+        None => return,
+    };
+    let oldest = match buffer.message_at(0) {
+        Some(msg) => msg.id,
+        None => return,
+    };
+    let guild = buffer.guild_id();
+    let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
+
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+
+        let mut msgs = match channel.messages(&*ctx, |retriever| {
+            retriever.before(oldest).limit(count.min(MAX_FETCH_COUNT))
+        }) {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                crate::plugin_print(&format!("Unable to load older messages: {}", e));
+                return;
+            },
+        };
+        msgs.sort_by_key(|msg| msg.id);
+
+        on_main(move |weecord| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let buf = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                Some(buf) => buf,
+                None => return,
+            };
+            // Another page may have been loaded in the meantime
+            if buf.message_at(0).map(|msg| msg.id) != Some(oldest) {
+                return;
+            }
+            if msgs.is_empty() {
+                buf.print(&format!(
+                    "{}\tThis is the beginning of the channel",
+                    weecord.get_prefix("network")
+                ));
+                return;
+            }
+
+            let unknown_users = buf.prepend_messages(&ctx.cache, &msgs);
+
+            if let Some(guild) = guild {
+                request_members(&*ctx, guild, channel, unknown_users);
+            }
+        });
+    });
+}
+
 pub fn load_pin_buffer_history(buffer: &MessageManager) {
     let channel = match utils::pins_for_channel(&buffer) {
         Some(ch) => ch,
@@ -440,20 +936,21 @@ pub fn load_pin_buffer_history(buffer: &MessageManager) {
     buffer.set_history_loaded();
     buffer.clear();
     let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
 
     std::thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
 
-        let pins = match channel.pins(ctx) {
+        let pins = match channel.pins(&*ctx) {
             Ok(pins) => pins,
             Err(_) => return,
         };
 
         on_main(move |weecord| {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
@@ -469,9 +966,10 @@ pub fn load_pin_buffer_history(buffer: &MessageManager) {
     });
 }
 
-pub fn load_pin_buffer_history_for_id(id: ChannelId) {
+pub fn load_pin_buffer_history_for_id(account: &str, id: ChannelId) {
+    let buffer_name = format!("{}Pins.{}", utils::account_prefix(account), id);
     on_main(move |weecord| {
-        if let Some(buffer) = weecord.buffer_manager.get_buffer(&format!("Pins.{}", id)) {
+        if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
             load_pin_buffer_history(&buffer)
         };
     })
@@ -488,76 +986,229 @@ pub fn load_history(
         return;
     };
     let guild = buffer.guild_id();
+    let account = buffer.account();
 
     buffer.clear();
     buffer.set_history_loaded();
+    buffer.set_detached(false);
+    buffer.set_marked_message(None);
+
+    // Show the locally stored history right away, then only fetch the messages that were missed
+    let last_stored = match crate::discord::get_ctx(&account) {
+        Some(ctx) => buffer.load_stored_history(&ctx.cache, fetch_count as usize),
+        None => None,
+    };
 
     let buffer_name = buffer.get_name().to_string();
 
     std::thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
 
-        if let Ok(msgs) = channel.messages(ctx, |retriever| retriever.limit(fetch_count as u64)) {
-            on_main(move |weechat| {
-                let ctx = match crate::discord::get_ctx() {
-                    Some(ctx) => ctx,
-                    _ => return,
-                };
-                let mut unknown_users = HashSet::new();
-                let buf = match weechat.buffer_manager.get_buffer(&buffer_name) {
-                    Some(buf) => buf,
-                    None => return,
-                };
+        // Messages are returned newest first, and `contiguous` is true when they directly follow
+        // the stored history
+        let (msgs, contiguous) = match last_stored.map(|last_stored| {
+            channel.messages(&*ctx, |retriever| {
+                retriever.after(last_stored).limit(MAX_FETCH_COUNT)
+            })
+        }) {
+            Some(Ok(mut msgs)) if (msgs.len() as u64) < MAX_FETCH_COUNT => {
+                msgs.sort_by(|a, b| b.id.cmp(&a.id));
+                (msgs, true)
+            },
+            _ => match channel.messages(&*ctx, |retriever| retriever.limit(fetch_count as u64)) {
+                Ok(msgs) => (msgs, false),
+                Err(_) => return,
+            },
+        };
 
-                if let Some(read_state) = ctx.cache.read().read_state.get(&channel) {
-                    let unread_in_page = msgs.iter().any(|m| m.id == read_state.last_message_id);
-
-                    if unread_in_page {
-                        let mut backlog = true;
-                        for msg in msgs.into_iter().rev() {
-                            unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
-
-                            if backlog {
-                                buf.mark_read();
-                                buf.clear_hotlist();
-                            }
-                            if msg.id == read_state.last_message_id {
-                                backlog = false;
-                            }
+        on_main(move |weechat| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let mut unknown_users = HashSet::new();
+            let buf = match weechat.buffer_manager.get_buffer(&buffer_name) {
+                Some(buf) => buf,
+                None => return,
+            };
+            if !contiguous {
+                buf.clear();
+            }
+
+            if let Some(read_state) = ctx.cache.read().read_state.get(&channel) {
+                let unread_in_page = msgs.iter().any(|m| m.id == read_state.last_message_id);
+
+                if unread_in_page {
+                    let mut backlog = true;
+                    for msg in msgs.into_iter().rev() {
+                        unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
+
+                        if backlog {
+                            buf.mark_read();
+                            buf.clear_hotlist();
                         }
-                    } else {
-                        buf.mark_read();
-                        buf.clear_hotlist();
-                        for msg in msgs.into_iter().rev() {
-                            unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
+                        if msg.id == read_state.last_message_id {
+                            backlog = false;
                         }
                     }
                 } else {
+                    buf.mark_read();
+                    buf.clear_hotlist();
                     for msg in msgs.into_iter().rev() {
                         unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
                     }
                 }
-                if let Some(guild) = guild {
-                    let msg = json::object! {
-                        "op" =>  OpCode::GetGuildMembers.num(),
-                        "d" => json::object! {
-                            "guild_id" => guild.0.to_string(),
-                            "user_ids" => (unknown_users.iter().map(|id| id.to_string())).collect::<Vec<_>>(),
-                            "nonce" => channel.0.to_string(),
-                        }
-                    };
-                    ctx.shard
-                        .websocket_message(gateway::Message::Text(msg.to_string()));
+            } else {
+                for msg in msgs.into_iter().rev() {
+                    unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
                 }
-                let _ = completion_sender.send(());
-            });
+            }
+            if let Some(guild) = guild {
+                request_members(&*ctx, guild, channel, unknown_users);
+            }
+            let _ = completion_sender.send(());
+        });
+    });
+}
+
+/// Remember the newest message in each buffer of an account that lost its connection, for
+/// `load_missed_messages`
+pub fn save_missed_after(account: &str) {
+    let account = account.to_owned();
+    on_main(move |weecord| {
+        for buffer in weecord.buffer_manager.buffers() {
+            if buffer.account() == account {
+                buffer.save_missed_after();
+            }
         }
     });
 }
 
+/// Fetch the messages an account missed while it was reconnecting into each of its buffers,
+/// printing a status line in the buffers that missed any
+pub fn load_missed_messages(account: &str) {
+    let account = account.to_owned();
+    on_main(move |weecord| {
+        let buffers: Vec<_> = weecord
+            .buffer_manager
+            .buffers()
+            .into_iter()
+            .filter(|buffer| buffer.account() == account)
+            .filter_map(|buffer| {
+                let last = buffer.take_missed_after()?;
+                // Buffers that are showing older messages or haven't loaded their history yet
+                // load the latest messages on their own
+                if !buffer.history_loaded() || buffer.detached() || utils::is_forum_buffer(&buffer)
+                {
+                    return None;
+                }
+                Some((
+                    buffer.get_name().to_string(),
+                    buffer.guild_id(),
+                    buffer.channel_id()?,
+                    last,
+                ))
+            })
+            .collect();
+        if buffers.is_empty() {
+            return;
+        }
+
+        std::thread::spawn(move || {
+            for (buffer_name, guild, channel, last) in buffers {
+                let ctx = match crate::discord::get_ctx(&account) {
+                    Some(ctx) => ctx,
+                    _ => return,
+                };
+                let mut msgs = match channel.messages(&*ctx, |retriever| {
+                    retriever.after(last).limit(MAX_FETCH_COUNT)
+                }) {
+                    Ok(msgs) => msgs,
+                    Err(_) => continue,
+                };
+                if msgs.is_empty() {
+                    continue;
+                }
+                msgs.sort_by_key(|msg| msg.id);
+                let truncated = msgs.len() as u64 == MAX_FETCH_COUNT;
+
+                let account = account.clone();
+                on_main(move |weecord| {
+                    let ctx = match crate::discord::get_ctx(&account) {
+                        Some(ctx) => ctx,
+                        _ => return,
+                    };
+                    let buf = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                        Some(buf) => buf,
+                        None => return,
+                    };
+                    // The buffer may have moved to older messages in the meantime
+                    if buf.detached() {
+                        return;
+                    }
+                    // Messages received since the reconnect may already be printed
+                    let msgs: Vec<_> = msgs
+                        .into_iter()
+                        .filter(|msg| buf.get_message(&msg.id).is_none())
+                        .collect();
+                    if msgs.is_empty() {
+                        return;
+                    }
+
+                    // Missed messages go before the ones that were received since the reconnect,
+                    // so the status line follows them
+                    let muted = utils::buffer_is_muted(&buf);
+                    let unknown_users: HashSet<_> = buf
+                        .add_missed_messages(&ctx.cache, &msgs, |msg| {
+                            !msg.is_own(&ctx.cache) && !muted
+                        })
+                        .into_iter()
+                        .collect();
+                    buf.print(&format!(
+                        "{}\tReconnected, loaded {} missed message{}",
+                        weecord.get_prefix("network"),
+                        msgs.len(),
+                        if msgs.len() == 1 { "" } else { "s" }
+                    ));
+                    if truncated {
+                        buf.print(&format!(
+                            "{}\tMore messages were missed, use /discord rehistory to load the \
+                             latest messages",
+                            weecord.get_prefix("network")
+                        ));
+                    }
+                    if let Some(guild) = guild {
+                        request_members(&*ctx, guild, channel, unknown_users);
+                    }
+                });
+            }
+        });
+    });
+}
+
+/// Request unknown members from the gateway, a `guild_members_chunk` event tagged with the channel
+/// will be received in response
+fn request_members(
+    ctx: &Context,
+    guild: GuildId,
+    channel: ChannelId,
+    users: impl IntoIterator<Item = UserId>,
+) {
+    let msg = json::object! {
+        "op" =>  OpCode::GetGuildMembers.num(),
+        "d" => json::object! {
+            "guild_id" => guild.0.to_string(),
+            "user_ids" => users.into_iter().map(|id| id.to_string()).collect::<Vec<_>>(),
+            "nonce" => channel.0.to_string(),
+        }
+    };
+    ctx.shard
+        .websocket_message(gateway::Message::Text(msg.to_string()));
+}
+
 pub fn load_dm_nicks(buffer: &MessageManager, channel: &PrivateChannel) {
     let weechat = buffer.get_weechat();
     let use_presence = crate::upgrade_plugin(&weechat).config.use_presence.value();
@@ -568,7 +1219,7 @@ pub fn load_dm_nicks(buffer: &MessageManager, channel: &PrivateChannel) {
         buffer.set_nicks_loaded();
         buffer.enable_nicklist();
 
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&buffer.account()) {
             Some(ctx) => ctx,
             _ => return,
         };
@@ -620,19 +1271,24 @@ pub fn load_nicks(buffer: &Buffer) {
     } else {
         return;
     };
+    // Thread members are not tracked, so show who can see the parent channel instead
+    let channel_id = utils::parent_channel(buffer).unwrap_or(channel_id);
 
     buffer.set_nicks_loaded();
     buffer.enable_nicklist();
 
+    let account = buffer.account();
     let sealed_buffer = buffer.seal();
 
     std::thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
 
-        let guild = guild_id.to_guild_cached(ctx).expect("No guild cache item");
+        let guild = guild_id
+            .to_guild_cached(&*ctx)
+            .expect("No guild cache item");
 
         // TODO: What to do with more than 1000 members?
         // NOTE: using `guild.read().members` 403s and invalidates a users verification status
@@ -641,7 +1297,7 @@ pub fn load_nicks(buffer: &Buffer) {
         drop(guild);
 
         let () = on_main_blocking(move |weechat| {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
@@ -649,14 +1305,16 @@ pub fn load_nicks(buffer: &Buffer) {
             let use_presence = weechat.config.use_presence.value();
 
             let buffer = sealed_buffer.unseal(&weechat);
-            let guild = guild_id.to_guild_cached(ctx).expect("No guild cache item");
+            let guild = guild_id
+                .to_guild_cached(&*ctx)
+                .expect("No guild cache item");
 
             let has_crown = guild_has_crown(&guild.read());
 
             for member in members {
                 add_member_to_nicklist(
                     weechat,
-                    &ctx,
+                    &*ctx,
                     &buffer,
                     channel_id,
                     &guild,
@@ -753,25 +1411,27 @@ fn add_member_to_nicklist(
     );
 }
 
-pub fn update_nick() {
-    let ctx = match crate::discord::get_ctx() {
+pub fn update_nick(account: &str) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
     let current_user = ctx.cache.read().user.clone();
 
-    for guild in current_user.guilds(ctx).expect("Unable to fetch guilds") {
+    for guild in current_user.guilds(&*ctx).expect("Unable to fetch guilds") {
         // TODO: Colors?
-        let nick = if let Ok(current_member) = guild.id.member(ctx, current_user.id) {
+        let nick = if let Ok(current_member) = guild.id.member(&*ctx, current_user.id) {
             format!("@{}", current_member.display_name())
         } else {
             format!("@{}", current_user.name)
         };
 
-        let channels = guild.id.channels(ctx).expect("Unable to fetch channels");
+        let channels = guild.id.channels(&*ctx).expect("Unable to fetch channels");
+        let account = account.to_owned();
         on_main(move |weechat| {
             for channel_id in channels.keys() {
-                let string_channel = utils::buffer_id_for_channel(Some(guild.id), *channel_id);
+                let string_channel =
+                    utils::buffer_id_for_channel(&account, Some(guild.id), *channel_id);
                 let nick = nick.to_owned();
                 if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
                     buffer.set_localvar("nick", &nick);
@@ -782,7 +1442,7 @@ pub fn update_nick() {
     }
 }
 
-pub fn update_member_nick(old: &Option<Member>, new: &Member) {
+pub fn update_member_nick(account: &str, old: &Option<Member>, new: &Member) {
     let old_nick = if let Some(old) = old.as_ref().map(Member::display_name) {
         old
     } else {
@@ -795,27 +1455,29 @@ pub fn update_member_nick(old: &Option<Member>, new: &Member) {
 
     if old_nick != new_nick {
         let old_nick = old_nick.to_owned().to_string();
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(account) {
             Some(ctx) => ctx,
             _ => return,
         };
 
-        let channels = guild_id.channels(ctx).expect("Unable to fetch channels");
+        let channels = guild_id.channels(&*ctx).expect("Unable to fetch channels");
+        let account = account.to_owned();
 
         on_main(move |weechat| {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
             for channel_id in channels.keys() {
-                let string_channel = utils::buffer_id_for_channel(Some(guild_id), *channel_id);
+                let string_channel =
+                    utils::buffer_id_for_channel(&account, Some(guild_id), *channel_id);
                 if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
                     if let Some(nick) = buffer.search_nick(&old_nick, None) {
                         nick.remove();
-                        if let Some(guild) = guild_id.to_guild_cached(&ctx) {
+                        if let Some(guild) = guild_id.to_guild_cached(&*ctx) {
                             add_member_to_nicklist(
                                 weechat,
-                                &ctx,
+                                &*ctx,
                                 &buffer,
                                 *channel_id,
                                 &guild,
@@ -1,12 +1,16 @@
 use crate::{
-    buffers, discord, on_main_blocking, plugin_print, upgrade_plugin, utils,
+    buffers, discord, on_main, on_main_blocking, plugin_print, upgrade_plugin, utils,
     utils::{BufferExt, ChannelExt, GuildOrChannel},
     weechat_utils::MessageManager,
     Discord,
 };
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
-use serenity::model::{gateway::Activity, user::OnlineStatus};
+use serenity::model::{
+    gateway::Activity,
+    id::{ChannelId, MessageId},
+    user::OnlineStatus,
+};
 use std::{borrow::Cow, collections::VecDeque, sync::Arc};
 use weechat::{Buffer, CommandHook, ConfigOption, ReturnCode, Weechat};
 
@@ -79,33 +83,53 @@ fn run_command(buffer: &Buffer, cmd: &str) {
         return;
     }
 
+    // Commands act on the account of the current buffer
+    let account = buffer.account();
+
     match args.base {
-        "connect" => weecord.connect(),
-        "disconnect" => disconnect(weecord),
+        "connect" => weecord.connect(args.args.front().map(|a| discord::parse_account_name(a))),
+        "disconnect" => disconnect(&args),
+        "info" => info(
+            weecord,
+            args.args
+                .front()
+                .map_or(account.as_str(), |a| discord::parse_account_name(a)),
+        ),
         "irc-mode" => irc_mode(weecord),
         "discord-mode" => discord_mode(weecord),
         "token" => token(weecord, &args),
         "autostart" => autostart(weecord),
         "noautostart" => noautostart(weecord),
         "query" => {
-            crate::hook::handle_query(&args);
+            crate::hook::handle_query(buffer, &args);
         },
         "join" => {
-            join(weecord, &args, true);
+            join(weecord, &account, &args, true);
         },
-        "watch" => watch(weecord, &args),
-        "nowatch" => nowatch(weecord, &args),
-        "watched" => watched(weecord),
-        "autojoin" => autojoin(weecord, &args, buffer),
-        "noautojoin" => noautojoin(weecord, &args),
-        "autojoined" => autojoined(weecord),
-        "status" => status(&args),
+        "watch" => watch(weecord, &account, &args),
+        "nowatch" => nowatch(weecord, &account, &args),
+        "watched" => watched(weecord, &account),
+        "autojoin" => autojoin(weecord, &account, &args, buffer),
+        "noautojoin" => noautojoin(weecord, &account, &args),
+        "autojoined" => autojoined(weecord, &account),
+        "status" => status(&account, &args),
         "pins" | "pinned" => pins(weecord, buffer),
-        "game" => game(&args),
+        "game" => game(&account, &args),
         "upload" => upload(&args, buffer),
         "me" | "tableflip" | "unflip" | "shrug" | "spoiler" => {
             discord_fmt(args.base, args.rest, buffer)
         },
+        "reply" => reply(weecord, &args, buffer),
+        "search" => search(weecord, &args, buffer),
+        "goto" => goto(weecord, &args, buffer),
+        "more" => more(weecord, &args, buffer),
+        "thread" => thread(&args, buffer),
+        "post" => post(&args, buffer),
+        "edits" => edits(weecord, &args, buffer),
+        "spoilers" => spoilers(weecord, &args, buffer),
+        "retry" => {
+            discord::send_queue::retry(buffer, args.args.front().and_then(|id| id.parse().ok()))
+        },
         "rehistory" => {
             let buffer_name = buffer.get_name().to_string();
             if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
@@ -118,15 +142,24 @@ fn run_command(buffer: &Buffer, cmd: &str) {
     };
 }
 
-fn disconnect(_weechat: &Weechat) {
-    let mut discord = crate::discord::DISCORD.lock();
-    if discord.is_some() {
-        if let Some(discord) = discord.take() {
-            discord.shutdown();
-        };
-        plugin_print("Disconnected");
-    } else {
-        plugin_print("Already disconnected");
+fn disconnect(args: &Args) {
+    match args.args.front() {
+        Some(account) => {
+            let account = discord::parse_account_name(account);
+            let name = discord::account_display_name(account);
+            if discord::disconnect(account) {
+                plugin_print(&format!("Disconnected {}", name));
+            } else {
+                plugin_print(&format!("{} is not connected", name));
+            }
+        },
+        None => {
+            if discord::disconnect_all() {
+                plugin_print("Disconnected");
+            } else {
+                plugin_print("Already disconnected");
+            }
+        },
     }
 }
 
@@ -176,7 +209,7 @@ fn noautostart(weechat: &Weechat) {
     plugin_print("Discord will not load on startup");
 }
 
-pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode {
+pub(crate) fn join(_weechat: &Weechat, account: &str, args: &Args, verbose: bool) -> ReturnCode {
     if args.args.is_empty() && verbose {
         plugin_print("join requires an guild name and optional channel name");
         ReturnCode::Error
@@ -188,7 +221,7 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
         };
         let channel_name = args.next();
 
-        let ctx = match discord::get_ctx() {
+        let ctx = match discord::connection::ready_ctx(account) {
             Some(ctx) => ctx,
             _ => return ReturnCode::Error,
         };
@@ -198,10 +231,11 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
                 crate::utils::search_channel(&ctx.cache, guild_name, channel_name)
             {
                 let guild = guild.read();
-                buffers::create_guild_buffer(guild.id, &guild.name);
+                buffers::create_guild_buffer(account, guild.id, &guild.name);
                 // TODO: Add correct nick handling
                 buffers::create_buffer_from_channel(
                     &ctx.cache,
+                    account,
                     &guild.name,
                     &channel.read(),
                     &ctx.cache.read().user.name,
@@ -209,14 +243,38 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
                 );
                 return ReturnCode::OkEat;
             }
+            // The channel may be a forum, which serenity does not know about
+            if let Some(guild) = crate::utils::search_guild(&ctx.cache, guild_name) {
+                let guild = guild.read();
+                buffers::create_forum_buffers(
+                    account,
+                    guild.id,
+                    &guild.name,
+                    &ctx.cache.read().user.name,
+                    Some(channel_name),
+                );
+                return ReturnCode::OkEat;
+            }
         } else if let Some(guild) = crate::utils::search_guild(&ctx.cache, guild_name) {
             let guild = guild.read();
             let guild_id = guild.id;
+            buffers::create_forum_buffers(
+                account,
+                guild_id,
+                &guild.name,
+                &ctx.cache.read().user.name,
+                None,
+            );
             drop(guild);
 
             let channels = utils::flatten_guilds(&ctx, &[GuildOrChannel::Guild(guild_id)]);
 
-            buffers::create_buffers_from_flat_items(&ctx, &ctx.cache.read().user, &channels);
+            buffers::create_buffers_from_flat_items(
+                account,
+                &ctx,
+                &ctx.cache.read().user,
+                &channels,
+            );
             return ReturnCode::OkEat;
         }
         if verbose {
@@ -227,8 +285,12 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
     }
 }
 
-fn resolve_channel_id(guild_name: &str, channel_name: Option<&str>) -> Option<String> {
-    let ctx = match discord::get_ctx() {
+fn resolve_channel_id(
+    account: &str,
+    guild_name: &str,
+    channel_name: Option<&str>,
+) -> Option<String> {
+    let ctx = match discord::connection::ready_ctx(account) {
         Some(ctx) => ctx,
         _ => return None,
     };
@@ -241,6 +303,10 @@ fn resolve_channel_id(guild_name: &str, channel_name: Option<&str>) -> Option<St
                 Some(guild.read().id),
                 channel.read().id,
             ))
+        } else if let Some(forum) = crate::utils::search_guild(&ctx.cache, guild_name)
+            .and_then(|guild| discord::forums::find(account, guild.read().id, channel_name))
+        {
+            Some(crate::utils::unique_id(Some(forum.guild_id), forum.id))
         } else {
             plugin_print("Unable to find server and channel");
             None
@@ -269,7 +335,7 @@ fn remove_item(items: Cow<str>, old_item: String) -> String {
     return items.join(",");
 }
 
-fn watch(weechat: &Weechat, args: &Args) {
+fn watch(weechat: &Weechat, account: &str, args: &Args) {
     if args.args.is_empty() {
         plugin_print("watch requires a guild name and optional channel name");
         return;
@@ -281,7 +347,7 @@ fn watch(weechat: &Weechat, args: &Args) {
     };
     let channel_name = args.next();
 
-    let new_channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let new_channel_id = match resolve_channel_id(account, guild_name, channel_name.cloned()) {
         Some(cid) => cid,
         None => return,
     };
@@ -298,7 +364,7 @@ fn watch(weechat: &Weechat, args: &Args) {
     }
 }
 
-fn nowatch(weechat: &Weechat, args: &Args) {
+fn nowatch(weechat: &Weechat, account: &str, args: &Args) {
     if args.args.is_empty() {
         plugin_print("nowatch requires a guild name and optional channel name");
         return;
@@ -310,7 +376,7 @@ fn nowatch(weechat: &Weechat, args: &Args) {
     };
     let channel_name = args.next();
 
-    let new_channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let new_channel_id = match resolve_channel_id(account, guild_name, channel_name.cloned()) {
         Some(cid) => cid,
         None => return,
     };
@@ -330,11 +396,79 @@ fn nowatch(weechat: &Weechat, args: &Args) {
     }
 }
 
-fn watched(weechat: &Weechat) {
+fn info(weecord: &Discord, account: &str) {
+    let state = discord::connection::state(account);
+    weecord.print("");
+    weecord.print(&format!(
+        "Account {}: {}",
+        discord::account_display_name(account),
+        state
+    ));
+    weecord.print(&format!(
+        "  Session: {}",
+        discord::connection::session_id(account).unwrap_or_else(|| "none".to_owned())
+    ));
+    for shard in discord::shards(account) {
+        let latency = shard
+            .latency
+            .map(|latency| format!("{}ms", latency.as_millis()))
+            .unwrap_or_else(|| "unknown".to_owned());
+        weecord.print(&format!(
+            "  Shard {}: {:?}, latency {}",
+            shard.id, shard.stage, latency
+        ));
+    }
+
+    let buffers = weecord
+        .buffer_manager
+        .buffers()
+        .iter()
+        .filter(|buffer| buffer.account() == account)
+        .count();
+    weecord.print(&format!("  Open buffers: {}", buffers));
+    weecord.print(&format!(
+        "  Unread mentions: {}",
+        discord::mentions::count(account)
+    ));
+
+    let ctx = match discord::get_ctx(account) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    {
+        let cache = ctx.cache.read();
+        weecord.print(&format!(
+            "  Cache: {} guilds, {} channels, {} private channels, {} users, {} presences",
+            cache.guilds.len(),
+            cache.channels.len(),
+            cache.private_channels.len(),
+            cache.users.len(),
+            cache.presences.len()
+        ));
+    }
+
+    let routes = ctx.http.ratelimiter.routes();
+    let routes = routes.read();
+    let exhausted: Vec<_> = routes
+        .iter()
+        .filter(|(_, ratelimit)| ratelimit.lock().remaining() == 0)
+        .map(|(route, _)| format!("{:?}", route))
+        .collect();
+    weecord.print(&format!(
+        "  Rate limits: {} routes, {} exhausted",
+        routes.len(),
+        exhausted.len()
+    ));
+    for route in exhausted {
+        weecord.print(&format!("    {}", route));
+    }
+}
+
+fn watched(weechat: &Weechat, account: &str) {
     let mut channels = Vec::new();
     let mut guilds = Vec::new();
 
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::connection::ready_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
@@ -355,17 +489,17 @@ fn watched(weechat: &Weechat) {
 
     weechat.print(&format!("Watched Servers: ({})", guilds.len()));
     for guild in guilds {
-        if let Some(guild) = guild.to_guild_cached(ctx) {
+        if let Some(guild) = guild.to_guild_cached(&*ctx) {
             weechat.print(&format!("  {}", guild.read().name));
         }
     }
 
     weechat.print(&format!("Watched Channels: ({})", channels.len()));
     for (guild, channel) in channels {
-        if let Ok(channel) = channel.to_channel(ctx) {
+        if let Ok(channel) = channel.to_channel(&*ctx) {
             let channel_name = channel.name();
             if let Some(guild) = guild {
-                let guild_name = if let Some(guild) = guild.to_guild_cached(&ctx) {
+                let guild_name = if let Some(guild) = guild.to_guild_cached(&*ctx) {
                     guild.read().name.to_owned()
                 } else {
                     guild.0.to_string()
@@ -380,7 +514,7 @@ fn watched(weechat: &Weechat) {
     }
 }
 
-fn autojoin(weechat: &Weechat, args: &Args, buffer: &Buffer) {
+fn autojoin(weechat: &Weechat, account: &str, args: &Args, buffer: &Buffer) {
     if args.args.is_empty() {
         plugin_print("autojoin requires a guild name and optional channel name");
         return;
@@ -392,7 +526,7 @@ fn autojoin(weechat: &Weechat, args: &Args, buffer: &Buffer) {
     };
     let channel_name = opts.next();
 
-    let new_channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let new_channel_id = match resolve_channel_id(account, guild_name, channel_name.cloned()) {
         Some(cid) => cid,
         None => return,
     };
@@ -412,7 +546,7 @@ fn autojoin(weechat: &Weechat, args: &Args, buffer: &Buffer) {
     }
 }
 
-fn noautojoin(weechat: &Weechat, args: &Args) {
+fn noautojoin(weechat: &Weechat, account: &str, args: &Args) {
     if args.args.is_empty() {
         plugin_print("noautojoin requires a guild name and optional channel name");
         return;
@@ -424,7 +558,7 @@ fn noautojoin(weechat: &Weechat, args: &Args) {
     };
     let channel_name = opts.next();
 
-    let channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let channel_id = match resolve_channel_id(account, guild_name, channel_name.cloned()) {
         Some(cid) => cid,
         None => return,
     };
@@ -446,11 +580,11 @@ fn noautojoin(weechat: &Weechat, args: &Args) {
     }
 }
 
-fn autojoined(weechat: &Weechat) {
+fn autojoined(weechat: &Weechat, account: &str) {
     let mut channels = Vec::new();
     let mut guilds = Vec::new();
 
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::connection::ready_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
@@ -471,17 +605,17 @@ fn autojoined(weechat: &Weechat) {
 
     weechat.print(&format!("Autojoin Servers: ({})", guilds.len()));
     for guild in guilds {
-        if let Some(guild) = guild.to_guild_cached(ctx) {
+        if let Some(guild) = guild.to_guild_cached(&*ctx) {
             weechat.print(&format!("  {}", guild.read().name));
         }
     }
 
     weechat.print(&format!("Autojoin Channels: ({})", channels.len()));
     for (guild, channel) in channels {
-        if let Ok(channel) = channel.to_channel(ctx) {
+        if let Ok(channel) = channel.to_channel(&*ctx) {
             let channel_name = channel.name();
             if let Some(guild) = guild {
-                let guild_name = if let Some(guild) = guild.to_guild_cached(&ctx) {
+                let guild_name = if let Some(guild) = guild.to_guild_cached(&*ctx) {
                     guild.read().name.to_owned()
                 } else {
                     guild.0.to_string()
@@ -496,8 +630,8 @@ fn autojoined(weechat: &Weechat) {
     }
 }
 
-fn status(args: &Args) {
-    let ctx = match crate::discord::get_ctx() {
+fn status(account: &str, args: &Args) {
+    let ctx = match discord::connection::ready_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
@@ -530,22 +664,23 @@ fn pins(weechat: &Discord, buffer: &Buffer) {
         None => return,
     };
 
-    let ctx = match crate::discord::get_ctx() {
+    let account = buffer.account();
+    let ctx = match discord::connection::ready_ctx(&account) {
         Some(ctx) => ctx,
         _ => return,
     };
 
-    let channel = match channel_id.to_channel_cached(ctx) {
+    let channel = match channel_id.to_channel_cached(&*ctx) {
         Some(ch) => ch,
         None => return,
     };
 
-    buffers::create_pins_buffer(weechat, &channel);
-    buffers::load_pin_buffer_history_for_id(channel.id());
+    buffers::create_pins_buffer(weechat, &account, &channel);
+    buffers::load_pin_buffer_history_for_id(&account, channel.id());
 }
 
-fn game(args: &Args) {
-    let ctx = match crate::discord::get_ctx() {
+fn game(account: &str, args: &Args) {
+    let ctx = match discord::connection::ready_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
@@ -600,11 +735,11 @@ fn upload(args: &Args, buffer: &Buffer) {
         } else {
             return;
         };
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match discord::connection::ready_ctx(&buffer.account()) {
             Some(ctx) => ctx,
             _ => return,
         };
-        match channel.send_files(ctx, vec![full], |m| m) {
+        match channel.send_files(&*ctx, vec![full], |m| m) {
             Ok(_) => plugin_print("File uploaded successfully"),
             Err(e) => {
                 if let serenity::Error::Model(serenity::model::ModelError::MessageTooLong(_)) = e {
@@ -644,7 +779,7 @@ fn format_option_change<T: std::fmt::Display>(
 }
 
 fn discord_fmt(cmd: &str, msg: &str, buffer: &Buffer) {
-    let msg = match cmd {
+    let wrap = |msg: &str| match cmd {
         "me" => format!("_{}_", msg),
         "tableflip" => format!("{} (╯°□°）╯︵ ┻━┻", msg),
         "unflip" => format!("{} ┬─┬ ノ( ゜-゜ノ)", msg),
@@ -653,17 +788,537 @@ fn discord_fmt(cmd: &str, msg: &str, buffer: &Buffer) {
         _ => unreachable!(),
     };
 
-    let channel = if let Some(channel) = buffer.channel_id() {
-        channel
-    } else {
+    if buffer.channel_id().is_none() {
+        return;
+    }
+    // The text is formatted like any other message, before the markdown of the command is added
+    if discord::connection::queue_ctx(&buffer.account()).is_some() {
+        let content = utils::format_outgoing(&buffer.get_weechat(), msg);
+        discord::send_queue::send(buffer, wrap(&content), &wrap(msg));
+    }
+}
+
+/// A message given as a command argument
+enum MessageArg {
+    /// `#n`, the nth most recent message
+    Line(usize),
+    /// A full message id
+    Id(MessageId),
+}
+
+/// Parse `#n` or a message id, anything else is rejected so a number can't mean either
+fn parse_message_arg(arg: &str) -> Option<MessageArg> {
+    if arg.starts_with('#') {
+        return arg[1..]
+            .parse()
+            .ok()
+            .filter(|&n| n > 0)
+            .map(MessageArg::Line);
+    }
+    // Snowflakes are at least 17 digits long
+    if arg.len() >= 17 && arg.bytes().all(|b| b.is_ascii_digit()) {
+        return arg.parse().ok().map(|id| MessageArg::Id(MessageId(id)));
+    }
+    None
+}
+
+/// Whether an argument was probably meant as a message, but is not `#n` or a message id
+fn is_invalid_message_arg(arg: &str) -> bool {
+    let digits = arg.trim_start_matches('#');
+    parse_message_arg(arg).is_none()
+        && !digits.is_empty()
+        && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn reply(weecord: &Discord, args: &Args, buffer: &Buffer) {
+    if args.args.is_empty() {
+        plugin_print("reply requires a message");
+        return;
+    }
+
+    let manager = match weecord.buffer_manager.get_buffer(&buffer.get_name()) {
+        Some(manager) => manager,
+        None => return,
+    };
+
+    // The first argument can either be `#n`, a message id, or the start of the reply itself
+    let first = *args.args.front().unwrap();
+    let (target, text) = match parse_message_arg(first) {
+        Some(_) if args.args.len() == 1 => {
+            plugin_print("reply requires a message");
+            return;
+        },
+        Some(MessageArg::Line(n)) => (manager.nth_message(n), args.rest[first.len()..].trim()),
+        Some(MessageArg::Id(id)) => {
+            let target = manager.get_message(&id).or_else(|| {
+                let ctx = discord::get_ctx(&buffer.account())?;
+                buffer.channel_id()?.message(&*ctx, id).ok()
+            });
+            (target, args.rest[first.len()..].trim())
+        },
+        None if is_invalid_message_arg(first) => {
+            plugin_print("reply requires #<n> for the nth most recent message, or a message id");
+            return;
+        },
+        None => (manager.nth_message(1), args.rest),
+    };
+
+    match target {
+        Some(target) => crate::hook::send_reply(buffer, &target, text),
+        None => plugin_print("Unable to find message to reply to"),
+    }
+}
+
+fn search(weecord: &Discord, args: &Args, buffer: &Buffer) {
+    if args.args.is_empty() {
+        plugin_print("search requires a query");
+        return;
+    }
+
+    let mut from = None;
+    let mut channel_name = None;
+    let mut has = None;
+    let mut local = false;
+    let mut terms = Vec::new();
+    let mut words = args.args.iter();
+    while let Some(&word) = words.next() {
+        match word {
+            "--from" => from = words.next(),
+            "--in" => channel_name = words.next(),
+            "--has" => has = words.next(),
+            "--local" => local = true,
+            term => terms.push(term),
+        }
+    }
+    let terms = terms.join(" ");
+    let account = buffer.account();
+
+    if local {
+        return local_search(weecord, &account, &terms);
+    }
+    let ctx = match discord::get_ctx(&account) {
+        Some(ctx) if discord::connection::state(&account).is_ready() => ctx,
+        _ => {
+            plugin_print(&format!(
+                "Not connected ({}), searching the stored messages instead",
+                discord::account_display_name(&account)
+            ));
+            return local_search(weecord, &account, &terms);
+        },
+    };
+
+    let scope = match (buffer.guild_id(), buffer.channel_id()) {
+        (Some(guild), _) => discord::search::SearchScope::Guild(guild),
+        (None, Some(channel)) => discord::search::SearchScope::Channel(channel),
+        (None, None) => {
+            plugin_print("search must be run in a Discord buffer");
+            return;
+        },
+    };
+    let guild = buffer
+        .guild_id()
+        .and_then(|guild| guild.to_guild_cached(&*ctx));
+
+    let mut query = discord::search::SearchQuery {
+        content: terms,
+        ..Default::default()
+    };
+    if let Some(from) = from {
+        let from = from.trim_start_matches('@').to_lowercase();
+        query.author = guild.as_ref().and_then(|guild| {
+            guild
+                .read()
+                .members_containing(&from, false, true)
+                .first()
+                .map(|member| member.user.read().id)
+        });
+        if query.author.is_none() {
+            plugin_print(&format!("Unable to find user \"{}\"", from));
+            return;
+        }
+    }
+    if let Some(channel_name) = channel_name {
+        let channel_name = channel_name.trim_start_matches('#');
+        query.channel = guild.as_ref().and_then(|guild| {
+            guild
+                .read()
+                .channels
+                .values()
+                .find(|channel| parsing::weechat_arg_strip(&channel.read().name) == channel_name)
+                .map(|channel| channel.read().id)
+        });
+        if query.channel.is_none() {
+            plugin_print(&format!("Unable to find channel \"{}\"", channel_name));
+            return;
+        }
+    }
+    if let Some(has) = has {
+        match has {
+            "link" | "file" => query.has = Some(has.to_owned()),
+            _ => {
+                plugin_print(&format!("Unknown search filter \"{}\"", has));
+                return;
+            },
+        }
+    }
+
+    let title = match &guild {
+        Some(guild) => format!("Search results in {}: {}", guild.read().name, args.rest),
+        None => format!("Search results: {}", args.rest),
+    };
+
+    std::thread::spawn(move || {
+        let results = discord::search::search(&account, scope, &query);
+        on_main(move |weecord| match results {
+            Ok(results) => {
+                buffers::create_search_buffer(weecord, &account, scope, &title, &results)
+            },
+            Err(e) => plugin_print(&format!("Search failed: {}", e)),
+        });
+    });
+}
+
+/// Search the on-disk message store
+fn local_search(weecord: &Discord, account: &str, query: &str) {
+    const MAX_RESULTS: usize = 25;
+
+    let store = match weecord.buffer_manager.message_store() {
+        Some(store) => store,
+        None => {
+            plugin_print("offline search requires weecord.main.message_log to be enabled");
+            return;
+        },
+    };
+
+    // Every stored channel is read, which can take a while
+    let (account, query) = (account.to_owned(), query.to_owned());
+    std::thread::spawn(move || {
+        let results = store.search(&account, &query, MAX_RESULTS);
+        on_main(move |weecord| {
+            weecord.print("");
+            weecord.print(&format!(
+                "Search results for \"{}\": ({})",
+                query,
+                results.len()
+            ));
+            for msg in results.iter().rev() {
+                let channel_name = discord::get_ctx(&account)
+                    .and_then(|ctx| msg.channel_id.to_channel_cached(&*ctx))
+                    .map(|channel| channel.name())
+                    .unwrap_or_else(|| msg.channel_id.0.to_string());
+                // Spoilers are shown if they are shown in the buffer of the message
+                let show_spoilers = weecord
+                    .buffer_manager
+                    .get_buffer(&utils::buffer_id_for_channel(
+                        &account,
+                        msg.guild_id,
+                        msg.channel_id,
+                    ))
+                    .map_or(false, |buffer| buffer.spoilers_shown_for(&msg.id));
+                let content = if show_spoilers {
+                    Cow::from(msg.content.as_str())
+                } else {
+                    discord::formatting::mask_spoilers(weecord, &msg.content)
+                };
+                weecord.print(&format!(
+                    "  [{}] #{} {}: {}",
+                    msg.timestamp.format("%Y-%m-%d %H:%M"),
+                    channel_name,
+                    msg.author.name,
+                    content
+                ));
+            }
+        });
+    });
+}
+
+fn goto(weecord: &Discord, args: &Args, buffer: &Buffer) {
+    let target = match args.args.front() {
+        Some(target) => *target,
+        None => {
+            plugin_print("goto requires a message id or link");
+            return;
+        },
+    };
+
+    if let Some(link) = parsing::parse_message_link(target) {
+        match buffers::open_channel_buffer(weecord, &buffer.account(), ChannelId(link.channel)) {
+            Some(channel_buffer) => {
+                buffers::load_page(
+                    &channel_buffer,
+                    buffers::Page::Around(MessageId(link.message)),
+                );
+                channel_buffer.switch_to();
+            },
+            None => plugin_print("Unable to find the channel of that message"),
+        }
         return;
+    }
+
+    let manager = match weecord.buffer_manager.get_buffer(&buffer.get_name()) {
+        Some(manager) => manager,
+        None => return,
+    };
+    let page = match target {
+        "latest" => {
+            manager.clear();
+            let count = weecord.config.message_fetch_count.value();
+            buffers::load_history(&manager, crossbeam_channel::unbounded().0, count);
+            return;
+        },
+        "prev" => manager
+            .message_at(0)
+            .map(|msg| buffers::Page::Before(msg.id)),
+        "next" => manager
+            .nth_message(1)
+            .map(|msg| buffers::Page::After(msg.id)),
+        id => match id.parse() {
+            Ok(id) => Some(buffers::Page::Around(MessageId(id))),
+            Err(_) => {
+                plugin_print(&format!("Invalid message id or link \"{}\"", id));
+                return;
+            },
+        },
     };
 
-    let ctx = match crate::discord::get_ctx() {
+    if let Some(page) = page {
+        buffers::load_page(&manager, page);
+    }
+}
+
+fn more(weecord: &Discord, args: &Args, buffer: &Buffer) {
+    let manager = match weecord.buffer_manager.get_buffer(&buffer.get_name()) {
+        Some(manager) => manager,
+        None => return,
+    };
+    let default_fetch_count = weecord.config.message_fetch_count.value();
+    let count = args
+        .args
+        .front()
+        .and_then(|c| c.parse::<u64>().ok())
+        .unwrap_or(default_fetch_count as u64);
+    buffers::load_older_messages(&manager, count);
+}
+
+fn spoilers(weecord: &Discord, args: &Args, buffer: &Buffer) {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(ctx) => ctx,
         _ => return,
     };
-    let _ = channel.send_message(&ctx.http, |m| m.content(msg));
+    let manager = match weecord.buffer_manager.get_buffer(&buffer.get_name()) {
+        Some(manager) => manager,
+        None => return,
+    };
+
+    let mut args = args.args.iter();
+    let shown = match args.next().copied() {
+        Some("show") => true,
+        Some("hide") => false,
+        _ => {
+            plugin_print("spoilers requires show or hide");
+            return;
+        },
+    };
+
+    // Without a message, the spoilers of the whole buffer are toggled
+    let msg = match args.next().map(|arg| parse_message_arg(arg)) {
+        None => {
+            manager.set_spoilers_shown(&ctx.cache, None, shown);
+            return;
+        },
+        Some(Some(MessageArg::Line(n))) => manager.nth_message(n),
+        Some(Some(MessageArg::Id(id))) => manager.get_message(&id),
+        Some(None) => {
+            plugin_print("spoilers requires #<n> for the nth most recent message, or a message id");
+            return;
+        },
+    };
+    match msg {
+        Some(msg) => manager.set_spoilers_shown(&ctx.cache, Some(&msg.id), shown),
+        None => plugin_print("Unable to find message"),
+    }
+}
+
+fn edits(weecord: &Discord, args: &Args, buffer: &Buffer) {
+    let manager = match weecord.buffer_manager.get_buffer(&buffer.get_name()) {
+        Some(manager) => manager,
+        None => return,
+    };
+
+    // Either the nth most recent message or a message id, like `reply`
+    let msg = match args.args.front().map(|arg| parse_message_arg(arg)) {
+        None => manager.nth_message(1),
+        Some(Some(MessageArg::Line(n))) => manager.nth_message(n),
+        Some(Some(MessageArg::Id(id))) => manager.get_message(&id),
+        Some(None) => {
+            plugin_print("edits requires #<n> for the nth most recent message, or a message id");
+            return;
+        },
+    };
+    let msg = match msg {
+        Some(msg) => msg,
+        None => {
+            plugin_print("Unable to find message");
+            return;
+        },
+    };
+
+    let history = manager.edit_history(&msg.id);
+    let prefix = weecord.get_prefix("network");
+    if history.is_empty() {
+        buffer.print(&format!(
+            "{}\tNo edits of this message have been seen",
+            prefix
+        ));
+        return;
+    }
+
+    buffer.print(&format!(
+        "{}\tEdit history of a message by {}:",
+        prefix, msg.author.name
+    ));
+    let show_spoilers = manager.spoilers_shown_for(&msg.id);
+    for (version, edit) in history.iter().chain(Some(&msg)).enumerate() {
+        let time = edit.edited_timestamp.unwrap_or(edit.timestamp);
+        let content = if show_spoilers {
+            Cow::from(edit.content.as_str())
+        } else {
+            discord::formatting::mask_spoilers(weecord, &edit.content)
+        };
+        buffer.print(&format!(
+            "{}\t{}[{}] {}{} {}",
+            prefix,
+            weecord.color("8"),
+            version + 1,
+            time.format("%Y-%m-%d %H:%M:%S"),
+            weecord.color("reset"),
+            content
+        ));
+    }
+}
+
+fn thread(args: &Args, buffer: &Buffer) {
+    let channel = match buffer.channel_id() {
+        Some(channel) => channel,
+        None => {
+            plugin_print("thread must be run in a Discord channel buffer");
+            return;
+        },
+    };
+    // Inside a thread, new threads are created in (and looked up from) the parent channel
+    let current_thread = utils::parent_channel(buffer).map(|parent| (parent, channel));
+    let parent = current_thread.map_or(channel, |(parent, _)| parent);
+    let account = buffer.account();
+
+    let mut words = args.args.iter().copied();
+    match words.next() {
+        Some("create") => {
+            let mut words = words.peekable();
+            let private = words.peek() == Some(&"--private");
+            if private {
+                words.next();
+            }
+            let name = words.collect::<Vec<_>>().join(" ");
+            if name.is_empty() {
+                plugin_print("thread create requires a name");
+                return;
+            }
+
+            std::thread::spawn(move || {
+                let result = discord::threads::create(&account, parent, &name, private);
+                on_main(move |weecord| match result {
+                    Ok(thread) => open_thread(weecord, &account, &thread),
+                    Err(e) => plugin_print(&format!("Unable to create thread: {}", e)),
+                });
+            });
+        },
+        Some("join") => {
+            let name = words.collect::<Vec<_>>().join(" ");
+            let thread = if name.is_empty() {
+                current_thread.and_then(|(_, thread)| discord::threads::get(&account, thread))
+            } else {
+                discord::threads::threads_in(&account, parent)
+                    .into_iter()
+                    .find(|thread| parsing::weechat_arg_strip(&thread.name) == name)
+            };
+            let thread = match thread {
+                Some(thread) => thread,
+                None => {
+                    plugin_print("Unable to find thread");
+                    return;
+                },
+            };
+
+            std::thread::spawn(move || {
+                let result = discord::threads::join(&account, thread.id);
+                on_main(move |weecord| match result {
+                    Ok(()) => open_thread(weecord, &account, &thread),
+                    Err(e) => plugin_print(&format!("Unable to join thread: {}", e)),
+                });
+            });
+        },
+        Some(action @ "leave") | Some(action @ "archive") => {
+            let thread = match current_thread {
+                Some((_, thread)) => thread,
+                None => {
+                    plugin_print(&format!("thread {} must be run in a thread buffer", action));
+                    return;
+                },
+            };
+            let action = action.to_owned();
+
+            std::thread::spawn(move || {
+                let result = if action == "leave" {
+                    discord::threads::leave(&account, thread)
+                } else {
+                    discord::threads::archive(&account, thread)
+                };
+                if let Err(e) = result {
+                    on_main(move |_| plugin_print(&format!("Unable to {} thread: {}", action, e)));
+                }
+            });
+        },
+        _ => plugin_print("thread requires one of create, join, leave or archive"),
+    }
+}
+
+fn post(args: &Args, buffer: &Buffer) {
+    // Posts can also be created from inside another post of the forum
+    let account = buffer.account();
+    let forum = match utils::parent_channel(buffer)
+        .or_else(|| buffer.channel_id())
+        .and_then(|forum| discord::forums::get(&account, forum))
+    {
+        Some(forum) => forum,
+        None => {
+            plugin_print("post must be run in a forum buffer");
+            return;
+        },
+    };
+
+    let (title, content) = match args.rest.find('|') {
+        Some(split) => (args.rest[..split].trim(), args.rest[split + 1..].trim()),
+        None => (args.rest.trim(), ""),
+    };
+    if title.is_empty() || content.is_empty() {
+        plugin_print("post requires a title and a message: /discord post <title> | <message>");
+        return;
+    }
+    let (title, content) = (title.to_owned(), content.to_owned());
+
+    std::thread::spawn(move || {
+        let result = discord::forums::create_post(&account, forum.id, &title, &content);
+        on_main(move |weecord| match result {
+            Ok(post) => open_thread(weecord, &account, &post),
+            Err(e) => plugin_print(&format!("Unable to create post: {}", e)),
+        });
+    });
+}
+
+fn open_thread(weecord: &Discord, account: &str, thread: &discord::threads::Thread) {
+    if let Some(buffer) = buffers::open_channel_buffer(weecord, account, thread.id) {
+        buffer.switch_to();
+    }
 }
 
 fn rehistory(weecord: &Discord, args: &Args, buffer: &MessageManager) {
@@ -684,8 +1339,9 @@ Discord from the comfort of your favorite command-line IRC client!
 Source code available at https://github.com/terminal-discord/weechat-discord
 Originally by https://github.com/khyperia/weechat-discord",
     args: "
-    connect
-    disconnect
+    connect [<account>]
+    disconnect [<account>]
+    info [<account>]
     join
     query
     watch
@@ -704,10 +1360,21 @@ Originally by https://github.com/khyperia/weechat-discord",
     unflip
     shrug
     spoiler
+    reply [#<n>|<message_id>] <message>
+    search [--from <user>] [--in <channel>] [--has link|file] [--local] <query>
+    goto <message_id>|<message_link>|prev|next|latest
+    more [<n>]
+    thread create [--private] <name>|join [<name>]|leave|archive
+    post <title> | <message>
+    edits [#<n>|<message_id>]
+    spoilers show|hide [#<n>|<message_id>]
+    retry [<id>]
     rehistory",
     args_description: "
-    connect: sign in to discord and open chat buffers
-    disconnect: sign out of Discord
+    connect: sign in to discord and open chat buffers, for one account or all of them
+    disconnect: sign out of Discord, for one account or all of them
+    info: show the connection, cache and rate limit state of an account (default is the account of the current buffer)
+    <account>: the name of an account in weecord.account, or default for the account of weecord.main.token
     join: join a channel in irc mode by providing guild name and channel name
     query: open a dm with a user (for when there are no discord buffers open)
     irc-mode: enable irc-mode, meaning that weecord will not load all channels like the official client
@@ -721,19 +1388,35 @@ Originally by https://github.com/khyperia/weechat-discord",
     noautostart: disable autostart
     status: set your Discord online status
     token: set Discord login token
+    reply: reply to the nth most recent message with #<n> (default #1), or to a message id, a plain number that is not a full message id is rejected
+    search: search the messages of the current guild or DM, --local searches the stored message history, which is also used while offline
+    goto: load the messages around a message id or link, prev and next load older or newer messages from there, latest returns to the latest messages
+    more: load n older messages at the start of the buffer (also done when scrolling to the top)
+    thread: create a thread in the current channel, join a thread by name (or the current thread), or leave or archive the current thread
+    post: create a new post in the current forum
+    edits: show the previous versions of the nth most recent message with #<n> (default #1) or a message id, edits are shown inline if weecord.look.inline_edits is enabled
+    spoilers: reveal or hide the spoilers of the current buffer, or of the nth most recent message with #<n> or a message id
+    retry: send a message, edit, delete or reaction that failed again, by default the most recent one in the current channel
     rehistory: reload the history in the current buffer
     upload: upload a file to the current channel
 
 Examples:
   /discord token 123456789ABCDEF
   /discord connect
+  /discord connect work
   /discord autostart
   /discord disconnect
   /discord upload file.txt
+  /discord reply #2 sounds good
+  /discord search --from someone --has link release notes
+  /discord thread create --private planning
+  /discord spoilers show #2
+  /discord post Build fails on arm | Has anyone seen this error before?
 ",
     completion:
-"connect || \
-disconnect || \
+"connect %(weecord_account_completion) || \
+disconnect %(weecord_account_completion) || \
+info %(weecord_account_completion) || \
 query %(weecord_dm_completion) || \
 watch %(weecord_guild_completion) %(weecord_channel_completion) || \
 nowatch %(weecord_guild_completion) %(weecord_channel_completion) || \
@@ -755,6 +1438,15 @@ tableflip || \
 unflip || \
 shrug || \
 spoiler || \
+reply || \
+search --from|--in|--has|--local || \
+goto prev|next|latest || \
+more || \
+thread create|join|leave|archive || \
+post || \
+edits || \
+spoilers show|hide || \
+retry || \
 rehistory || \
 join %(weecord_guild_completion) %(weecord_channel_completion)",
 };
@@ -1,8 +1,20 @@
-use crate::{utils, utils::GuildOrChannel};
+use crate::{
+    discord::highlight::{CodeTheme, Theme},
+    plugin_print, utils,
+    utils::GuildOrChannel,
+    weechat_utils::config_section,
+};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
 use weechat::{
     BooleanOption, ConfigOption, ConfigSectionInfo, IntegerOption, StringOption, Weechat,
 };
 
+lazy_static! {
+    /// The last value of `weecord.look.code_colors` that was warned about
+    static ref WARNED_CODE_COLORS: Mutex<String> = Mutex::new(String::new());
+}
+
 pub struct Config {
     pub token: StringOption,
     pub watched_channels: StringOption,
@@ -12,8 +24,18 @@ pub struct Config {
     pub send_typing_events: BooleanOption,
     pub irc_mode: BooleanOption,
     pub message_fetch_count: IntegerOption,
+    pub message_log: BooleanOption,
+    pub escape_markdown: BooleanOption,
     pub user_typing_list_max: IntegerOption,
     pub user_typing_list_expanded: BooleanOption,
+    pub inline_edits: BooleanOption,
+    pub deleted_messages: IntegerOption,
+    pub code_theme: IntegerOption,
+    pub code_colors: StringOption,
+    pub spoiler_mask: StringOption,
+    pub hide_link_previews: BooleanOption,
+    pub image_previews: BooleanOption,
+    pub image_preview_width: IntegerOption,
     pub config: weechat::Config<()>,
 }
 
@@ -110,6 +132,28 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
+    let message_log = section.new_boolean_option(
+        "message_log",
+        "Store received messages on disk so history loads instantly and can be searched offline \
+         (requires a restart)",
+        true,
+        true,
+        false,
+        None,
+        None::<()>,
+    );
+
+    let escape_markdown = section.new_boolean_option(
+        "escape_markdown",
+        "Escape markdown in sent messages so they are sent exactly as typed, formatting can still \
+         be added with weechat's formatting keys (ctrl-c b, i, _)",
+        false,
+        false,
+        false,
+        None,
+        None::<()>,
+    );
+
     let user_typing_list_max = section.new_integer_option(
         "user_typing_list_max",
         "How many users will be displayed at most in the typing indicator",
@@ -133,6 +177,108 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
+    let section_info: ConfigSectionInfo<()> = ConfigSectionInfo {
+        name: "look",
+        ..Default::default()
+    };
+
+    let section = config.new_section(section_info);
+
+    let inline_edits = section.new_boolean_option(
+        "inline_edits",
+        "Show what changed below edited messages, instead of only marking them as edited",
+        false,
+        false,
+        false,
+        None,
+        None::<()>,
+    );
+
+    let deleted_messages = section.new_integer_option(
+        "deleted_messages",
+        "How deleted messages are shown: hide removes them, strike crosses them out and \
+         placeholder replaces their content",
+        "hide|strike|placeholder",
+        0,
+        0,
+        "hide",
+        "hide",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let code_theme = section.new_integer_option(
+        "code_theme",
+        "Color theme used to highlight code blocks",
+        "dark|light|none",
+        0,
+        0,
+        "dark",
+        "dark",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let code_colors = section.new_string_option(
+        "code_colors",
+        "Comma separated list of colors overriding the code theme, as token:color. Tokens are \
+         plain, keyword, string, comment, number, added, removed and hunk",
+        "",
+        "",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let spoiler_mask = section.new_string_option(
+        "spoiler_mask",
+        "Text shown in place of hidden spoilers, they can be revealed with /discord spoilers show",
+        "▒▒▒▒▒▒",
+        "▒▒▒▒▒▒",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let hide_link_previews = section.new_boolean_option(
+        "hide_link_previews",
+        "Hide the preview embeds of links that are already shown in the message",
+        true,
+        true,
+        false,
+        None,
+        None::<()>,
+    );
+
+    let image_previews = section.new_boolean_option(
+        "image_previews",
+        "Show a preview of image attachments drawn with unicode half blocks, images are \
+         downloaded when they are shown and cached in <weechatdir>/weecord/images",
+        false,
+        false,
+        false,
+        None,
+        None::<()>,
+    );
+
+    let image_preview_width = section.new_integer_option(
+        "image_preview_width",
+        "Width of image previews in columns",
+        "",
+        4,
+        200,
+        "32",
+        "32",
+        false,
+        None,
+        None::<()>,
+    );
+
+    // Named accounts are created when the config is read, or with /set
+    config_section::create_account_section(weechat, "weecord.main.token");
+
     config.read();
 
     Config {
@@ -144,13 +290,66 @@ pub fn init(weechat: &Weechat) -> Config {
         send_typing_events,
         irc_mode,
         message_fetch_count,
+        message_log,
+        escape_markdown,
         user_typing_list_max,
         user_typing_list_expanded,
+        inline_edits,
+        deleted_messages,
+        code_theme,
+        code_colors,
+        spoiler_mask,
+        hide_link_previews,
+        image_previews,
+        image_preview_width,
         config,
     }
 }
 
+/// How deleted messages are displayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletedMessages {
+    Hide,
+    Strike,
+    Placeholder,
+}
+
 impl Config {
+    pub fn deleted_messages(&self) -> DeletedMessages {
+        match self.deleted_messages.value() {
+            1 => DeletedMessages::Strike,
+            2 => DeletedMessages::Placeholder,
+            _ => DeletedMessages::Hide,
+        }
+    }
+
+    /// The names of the accounts in the `account` section, the default account is not included
+    pub fn accounts(&self) -> Vec<String> {
+        config_section::accounts()
+    }
+
+    pub fn code_theme(&self) -> Theme {
+        let base = match self.code_theme.value() {
+            1 => CodeTheme::Light,
+            2 => CodeTheme::None,
+            _ => CodeTheme::Dark,
+        };
+        let overrides = self.code_colors.value().to_string();
+        let (theme, unknown) = Theme::new(base, &overrides);
+
+        // The theme is built for every code block, so only warn once for each value
+        let mut warned = WARNED_CODE_COLORS.lock();
+        if !unknown.is_empty() && *warned != overrides {
+            plugin_print(&format!(
+                "Unrecognized weecord.look.code_colors overrides: {}, expected token:color with \
+                 token one of plain, keyword, string, comment, number, added, removed or hunk",
+                unknown.join(", ")
+            ));
+            *warned = overrides;
+        }
+        theme
+    }
+
     pub fn autojoin_channels(&self) -> Vec<GuildOrChannel> {
         self.autojoin_channels
             .value()
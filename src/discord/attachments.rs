@@ -0,0 +1,277 @@
+//! Attachment descriptions and image previews
+//!
+//! Previews are drawn with unicode half blocks, using the foreground color for the upper pixel
+//! and the background color for the lower one. Weechat draws the whole screen itself, so sixel
+//! and kitty graphics can't be shown inside a buffer. Thumbnails are cached on disk in
+//! `<weechatdir>/weecord/images`, and the most recently shown ones are also kept in memory.
+
+use crate::{on_main, utils};
+use image::RgbImage;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serenity::{model::prelude::*, utils::Colour};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+};
+use weechat::Weechat;
+
+/// Images larger than this are not downloaded for a preview
+const MAX_PREVIEW_SIZE: u64 = 8 * 1024 * 1024;
+/// Images with more pixels than this are not downloaded either, a small file can still decode
+/// to a huge image
+const MAX_PREVIEW_PIXELS: u64 = 40_000_000;
+/// The amount of decoded thumbnails kept in memory
+const MAX_THUMBNAILS: usize = 64;
+
+/// Decoded thumbnails, the least recently used one is dropped when there are too many
+#[derive(Default)]
+struct Thumbnails {
+    images: HashMap<AttachmentId, Arc<RgbImage>>,
+    /// Least recently used first
+    order: VecDeque<AttachmentId>,
+}
+
+impl Thumbnails {
+    fn get(&mut self, id: AttachmentId) -> Option<Arc<RgbImage>> {
+        let image = Arc::clone(self.images.get(&id)?);
+        self.touch(id);
+        Some(image)
+    }
+
+    fn insert(&mut self, id: AttachmentId, image: Arc<RgbImage>) {
+        self.images.insert(id, image);
+        self.touch(id);
+        while self.order.len() > MAX_THUMBNAILS {
+            if let Some(old) = self.order.pop_front() {
+                self.images.remove(&old);
+            }
+        }
+    }
+
+    fn touch(&mut self, id: AttachmentId) {
+        self.order.retain(|&other| other != id);
+        self.order.push_back(id);
+    }
+}
+
+lazy_static! {
+    static ref THUMBNAILS: Arc<Mutex<Thumbnails>> = Arc::new(Mutex::new(Thumbnails::default()));
+    static ref PENDING: Arc<Mutex<HashSet<AttachmentId>>> = Arc::new(Mutex::new(HashSet::new()));
+    /// Attachments that could not be downloaded or decoded
+    static ref FAILED: Arc<Mutex<HashSet<AttachmentId>>> = Arc::new(Mutex::new(HashSet::new()));
+}
+
+/// Guess the content type of an attachment from its file extension
+pub fn content_type(filename: &str) -> &'static str {
+    let extension = filename
+        .rsplit('.')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "txt" | "log" => "text/plain",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+/// Describe an attachment on a single line, with its name, size, content type and dimensions
+pub fn describe(weechat: &Weechat, attachment: &Attachment) -> String {
+    let mut details = vec![
+        format_size(attachment.size),
+        content_type(&attachment.filename).to_owned(),
+    ];
+    if let (Some(width), Some(height)) = (attachment.width, attachment.height) {
+        details.push(format!("{}×{}", width, height));
+    }
+
+    format!(
+        "{}{}{} {}({}){} {}",
+        weechat.color("bold"),
+        attachment.filename,
+        weechat.color("-bold"),
+        weechat.color("8"),
+        details.join(", "),
+        weechat.color("reset"),
+        attachment.proxy_url
+    )
+}
+
+fn cache_dir(weechat: &Weechat) -> Option<PathBuf> {
+    let home = weechat.info_get("weechat_dir", "")?;
+    Some(PathBuf::from(home.as_ref()).join("weecord").join("images"))
+}
+
+/// The amount of lines the preview of an image takes, it fits in a square of `width` pixels
+/// like the thumbnail, and every line is two pixels high
+fn preview_height(attachment: &Attachment, width: u32) -> Option<usize> {
+    let (image_width, image_height) = (attachment.width?, attachment.height?);
+    if image_width == 0 || image_height == 0 {
+        return None;
+    }
+    let height = if image_width >= image_height {
+        (u64::from(image_height) * u64::from(width) / u64::from(image_width)).max(1)
+    } else {
+        u64::from(width)
+    };
+    Some(((height + 1) / 2) as usize)
+}
+
+/// Render a preview of an image attachment of `msg`, `width` cells wide, spoiler images are only
+/// shown if `show_spoilers` is set
+///
+/// If the thumbnail is not cached yet, it is downloaded in the background. Until then, a
+/// placeholder with the height of the preview is shown, so the message can be redrawn in place
+/// once it is available.
+pub fn preview(
+    weechat: &Weechat,
+    attachment: &Attachment,
+    width: u32,
+    msg: &Message,
+    guild: Option<GuildId>,
+    show_spoilers: bool,
+) -> Option<Vec<String>> {
+    // Videos have dimensions too, but can't be decoded
+    if !content_type(&attachment.filename).starts_with("image/")
+        || attachment.size > MAX_PREVIEW_SIZE
+        || (attachment.filename.starts_with("SPOILER_") && !show_spoilers)
+    {
+        return None;
+    }
+    let (image_width, image_height) = (attachment.width?, attachment.height?);
+    if u64::from(image_width) * u64::from(image_height) > MAX_PREVIEW_PIXELS {
+        return None;
+    }
+    let height = preview_height(attachment, width)?;
+
+    let cached = THUMBNAILS.lock().get(attachment.id);
+    if let Some(thumbnail) = cached {
+        return Some(render_half_blocks(weechat, &thumbnail, height));
+    }
+
+    let dir = cache_dir(weechat)?;
+    let path = dir.join(format!("{}.png", attachment.id.0));
+    if let Ok(thumbnail) = image::open(&path) {
+        let thumbnail = Arc::new(thumbnail.to_rgb());
+        THUMBNAILS
+            .lock()
+            .insert(attachment.id, Arc::clone(&thumbnail));
+        return Some(render_half_blocks(weechat, &thumbnail, height));
+    }
+
+    let mut placeholder = vec![String::new(); height];
+    placeholder[0] = format!(
+        "{}(loading preview){}",
+        weechat.color("8"),
+        weechat.color("reset")
+    );
+    if FAILED.lock().contains(&attachment.id) {
+        return None;
+    }
+    if !PENDING.lock().insert(attachment.id) {
+        return Some(placeholder);
+    }
+    let attachment = attachment.clone();
+    let (channel, message) = (msg.channel_id, msg.id);
+    thread::spawn(move || {
+        let thumbnail = attachment
+            .download()
+            .ok()
+            .and_then(|bytes| image::load_from_memory(&bytes).ok())
+            // Every cell is two pixels high
+            .map(|image| image.thumbnail(width, width));
+        // A failed download is not retried, the placeholder is removed when the message is
+        // redrawn
+        if let Some(thumbnail) = thumbnail {
+            if fs::create_dir_all(&dir).is_ok() {
+                let _ = thumbnail.save(&path);
+            }
+            THUMBNAILS
+                .lock()
+                .insert(attachment.id, Arc::new(thumbnail.to_rgb()));
+        } else {
+            FAILED.lock().insert(attachment.id);
+        }
+        PENDING.lock().remove(&attachment.id);
+
+        on_main(move |weecord| {
+            // The message is shown in the buffers of every account that can see the channel
+            for account in crate::discord::connected_accounts() {
+                let ctx = match crate::discord::get_ctx(&account) {
+                    Some(ctx) => ctx,
+                    _ => continue,
+                };
+                let buffer_name = utils::buffer_id_for_channel(&account, guild, channel);
+                if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                    buffer.refresh_message(&ctx.cache, &message);
+                }
+            }
+        });
+    });
+    Some(placeholder)
+}
+
+/// Render an image with half blocks on exactly `height` lines, so it replaces its placeholder
+/// even if the image turned out to have other dimensions than the attachment said
+fn render_half_blocks(weechat: &Weechat, image: &RgbImage, height: usize) -> Vec<String> {
+    let color = |x, y| {
+        let pixel = image.get_pixel(x, y);
+        utils::rgb_to_ansi(Colour::from_rgb(pixel[0], pixel[1], pixel[2]))
+    };
+
+    (0..image.height())
+        .step_by(2)
+        .map(|y| {
+            let mut line = String::new();
+            for x in 0..image.width() {
+                let upper = color(x, y);
+                // An odd height leaves the last lower half empty
+                let code = if y + 1 < image.height() {
+                    format!("{},{}", upper, color(x, y + 1))
+                } else {
+                    upper.to_string()
+                };
+                line.push_str(&weechat.color(&code));
+                line.push('▀');
+            }
+            line.push_str(&weechat.color("reset"));
+            line
+        })
+        .chain(std::iter::repeat(String::new()))
+        .take(height)
+        .collect()
+}
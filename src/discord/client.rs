@@ -1,40 +1,215 @@
-use super::event_handler::Handler;
-use crate::Discord;
-use serenity::{client::bridge::gateway::ShardManager, model::gateway::Ready, prelude::*};
+use super::{
+    connection::{self, ConnectionState},
+    event_handler::Handler,
+};
+use crate::{on_main_blocking, Discord};
+use serenity::{
+    client::{bridge::gateway::ShardManager, ClientError},
+    gateway::{ConnectionStage, GatewayError},
+    http::{Http, HttpError},
+    model::gateway::Ready,
+    prelude::*,
+};
 use std::{
-    sync::{mpsc, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// The longest delay before the first reconnect attempt, doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Connections that lasted at least this long reset the backoff
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// The state of one shard of a client
+pub struct ShardInfo {
+    pub id: u64,
+    pub stage: ConnectionStage,
+    /// The time between the last heartbeat and its acknowledgement
+    pub latency: Option<Duration>,
+}
+
 pub struct DiscordClient {
-    shard_manager: Arc<Mutex<ShardManager>>,
+    /// The shard manager of the current client, which is replaced on every reconnect
+    shard_manager: Arc<Mutex<Arc<Mutex<ShardManager>>>>,
+    running: Arc<AtomicBool>,
 }
 
 impl DiscordClient {
+    /// Start a client that connects again whenever its gateway connection fails
+    ///
+    /// Short interruptions are resumed by serenity itself, this only starts a new session once
+    /// the shards have given up.
     pub fn start(
         weecord: &Discord,
+        account: &str,
         token: &str,
     ) -> Result<(DiscordClient, mpsc::Receiver<Ready>), serenity::Error> {
         let (tx, rx) = mpsc::channel();
-        let handler = Handler::new(weecord, Arc::new(Mutex::new(tx)));
-
-        let mut client = Client::new(token, handler)?;
-
-        let shard_manager = client.shard_manager.clone();
-        thread::spawn(move || {
-            if let Err(e) = client.start_shards(1) {
-                crate::on_main(move |weecord| {
-                    weecord.print(&format!(
-                        "discord: An error occurred connecting to discord: {}",
-                        e
-                    ));
-                });
-            }
-        });
-        Ok((DiscordClient { shard_manager }, rx))
+        let sender = Arc::new(Mutex::new(tx));
+        let handler = Handler::new(weecord, account, Arc::clone(&sender));
+
+        let client = Client::new(token, handler)?;
+
+        let shard_manager = Arc::new(Mutex::new(client.shard_manager.clone()));
+        let running = Arc::new(AtomicBool::new(true));
+        {
+            let shard_manager = Arc::clone(&shard_manager);
+            let running = Arc::clone(&running);
+            let account = account.to_owned();
+            let token = token.to_owned();
+            thread::spawn(move || {
+                let mut client = Some(client);
+                let mut attempt = 0;
+                loop {
+                    let started = Instant::now();
+                    let result = match client.take() {
+                        Some(client) => Ok(client),
+                        None => {
+                            let handler: Handler = on_main_blocking(|weecord| {
+                                Handler::new(weecord, &account, Arc::clone(&sender))
+                            });
+                            Client::new(&token, handler)
+                        },
+                    }
+                    .and_then(|mut client| {
+                        {
+                            let mut current = shard_manager.lock();
+                            if !running.load(Ordering::SeqCst) {
+                                return Ok(());
+                            }
+                            *current = client.shard_manager.clone();
+                        }
+                        client.start_shards(1)
+                    });
+
+                    // The client was shut down on purpose
+                    if !running.load(Ordering::SeqCst) {
+                        return;
+                    }
+
+                    let name = super::account_display_name(&account);
+                    // Reconnecting can't fix a token that Discord does not accept
+                    let rejected = match &result {
+                        Err(e) if is_auth_error(e) => true,
+                        _ => token_rejected(&token),
+                    };
+                    if rejected {
+                        crate::plugin_print(&format!(
+                            "Could not connect ({}): Discord rejected the token, set a valid \
+                             token and run /discord connect again",
+                            name
+                        ));
+                        super::disconnect(&account);
+                        return;
+                    }
+
+                    if started.elapsed() >= STABLE_CONNECTION {
+                        attempt = 0;
+                    }
+                    let delay = backoff(attempt);
+                    attempt += 1;
+
+                    let reason = match result {
+                        Ok(()) => "the connection was closed".to_owned(),
+                        Err(e) => e.to_string(),
+                    };
+                    if connection::has_been_ready(&account) {
+                        crate::plugin_print(&format!(
+                            "Connection lost ({}): {}, reconnecting in {}s",
+                            name,
+                            reason,
+                            delay.as_secs()
+                        ));
+                    } else {
+                        crate::plugin_print(&format!(
+                            "Could not connect ({}): {}, trying again in {}s",
+                            name,
+                            reason,
+                            delay.as_secs()
+                        ));
+                    }
+                    connection::set_state(&account, ConnectionState::Reconnecting);
+                    thread::sleep(delay);
+
+                    if !running.load(Ordering::SeqCst) {
+                        return;
+                    }
+                }
+            });
+        }
+        Ok((
+            DiscordClient {
+                shard_manager,
+                running,
+            },
+            rx,
+        ))
+    }
+
+    pub fn shards(&self) -> Vec<ShardInfo> {
+        let current = self.shard_manager.lock();
+        let manager = current.lock();
+        let mut shards: Vec<_> = manager
+            .runners
+            .lock()
+            .iter()
+            .map(|(id, runner)| ShardInfo {
+                id: id.0,
+                stage: runner.stage,
+                latency: runner.latency,
+            })
+            .collect();
+        shards.sort_by_key(|shard| shard.id);
+        shards
     }
 
     pub fn shutdown(&self) {
-        self.shard_manager.lock().shutdown_all();
+        let current = self.shard_manager.lock();
+        self.running.store(false, Ordering::SeqCst);
+        current.lock().shutdown_all();
+    }
+}
+
+/// Whether an error means that the token was not accepted
+fn is_auth_error(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Client(ClientError::InvalidToken)
+        | serenity::Error::Gateway(GatewayError::InvalidAuthentication)
+        | serenity::Error::Gateway(GatewayError::NoAuthentication) => true,
+        _ => false,
     }
 }
+
+/// Whether the api rejects a token
+///
+/// Shards that fail to identify shut down without passing on the reason, so the token is checked
+/// directly before connecting again. Other errors, like the api being unreachable, are retried.
+fn token_rejected(token: &str) -> bool {
+    match Http::new_with_token(token).get_current_user() {
+        Err(serenity::Error::Http(e)) => match *e {
+            HttpError::UnsuccessfulRequest(ref response) => response.status_code.as_u16() == 401,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Exponential backoff with jitter, so clients that lost their connection together don't all
+/// reconnect at the same moment
+fn backoff(attempt: u32) -> Duration {
+    let max = INITIAL_BACKOFF
+        .checked_mul(1 << attempt.min(16))
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF));
+    // The clock is random enough to spread reconnects out, without pulling in an rng
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos())
+        .unwrap_or_default();
+    let jitter = f64::from(nanos % 1000) / 1000.0;
+    max.mul_f64(0.5 + jitter / 2.0)
+}
@@ -0,0 +1,202 @@
+//! The connection state of every account
+//!
+//! Becoming ready sends the `weecord_connected` signal, and losing the connection sends
+//! `weecord_disconnected`, both with the account name as their argument. When an account becomes
+//! ready again after losing its connection, the messages it missed since then are fetched into its
+//! buffers.
+
+use crate::{on_main_later, plugin_print, weechat_utils::signal};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serenity::{client::Context, gateway::ConnectionStage};
+use std::{collections::HashMap, fmt, mem, sync::Arc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Identifying,
+    Ready,
+    Resuming,
+    Reconnecting,
+}
+
+impl ConnectionState {
+    pub fn is_ready(self) -> bool {
+        self == ConnectionState::Ready
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Identifying => "identifying",
+            ConnectionState::Ready => "connected",
+            ConnectionState::Resuming => "resuming",
+            ConnectionState::Reconnecting => "reconnecting",
+        };
+        f.write_str(name)
+    }
+}
+
+struct Connection {
+    state: ConnectionState,
+    /// Whether the account has been ready since it last connected on purpose
+    has_been_ready: bool,
+    session_id: Option<String>,
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Arc<Mutex<HashMap<String, Connection>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+pub fn state(account: &str) -> ConnectionState {
+    CONNECTIONS
+        .lock()
+        .get(account)
+        .map(|connection| connection.state)
+        .unwrap_or(ConnectionState::Disconnected)
+}
+
+/// Whether an account has been ready since it last connected on purpose
+pub fn has_been_ready(account: &str) -> bool {
+    CONNECTIONS
+        .lock()
+        .get(account)
+        .map_or(false, |connection| connection.has_been_ready)
+}
+
+/// The gateway session of an account, kept while it is resuming or reconnecting
+pub fn session_id(account: &str) -> Option<String> {
+    CONNECTIONS
+        .lock()
+        .get(account)
+        .and_then(|connection| connection.session_id.clone())
+}
+
+pub(crate) fn set_session_id(account: &str, session_id: &str) {
+    if let Some(connection) = CONNECTIONS.lock().get_mut(account) {
+        connection.session_id = Some(session_id.to_owned());
+    }
+}
+
+/// Move an account to a new state, sending a signal if it became or stopped being ready
+pub fn set_state(account: &str, new: ConnectionState) {
+    let (old, reconnected, signal) = {
+        let mut connections = CONNECTIONS.lock();
+        let connection = connections.entry(account.to_owned()).or_insert(Connection {
+            state: ConnectionState::Disconnected,
+            has_been_ready: false,
+            session_id: None,
+        });
+        let old = mem::replace(&mut connection.state, new);
+        let reconnected = new.is_ready() && !old.is_ready() && connection.has_been_ready;
+        // Only leaving ready sends the disconnected signal, an account that never connected
+        // did not lose anything
+        let signal = if new.is_ready() && !old.is_ready() {
+            Some("weecord_connected")
+        } else if old.is_ready() && !new.is_ready() {
+            Some("weecord_disconnected")
+        } else {
+            None
+        };
+        match new {
+            ConnectionState::Ready => connection.has_been_ready = true,
+            ConnectionState::Disconnected => {
+                connection.has_been_ready = false;
+                connection.session_id = None;
+            },
+            _ => {},
+        }
+        (old, reconnected, signal)
+    };
+
+    if old.is_ready() && !new.is_ready() && new != ConnectionState::Disconnected {
+        crate::buffers::save_missed_after(account);
+    }
+    if reconnected {
+        crate::buffers::load_missed_messages(account);
+    }
+
+    if old == new {
+        return;
+    }
+    let account = account.to_owned();
+    // Accounts start connecting while the plugin is initializing
+    on_main_later(move |weecord| {
+        if let Some(signal) = signal {
+            signal::send_string(weecord, signal, &account);
+        }
+        weecord.update_bar_item("discord_status");
+    });
+}
+
+/// Follow the stages of the gateway connection of an account
+pub(crate) fn stage_changed(account: &str, stage: ConnectionStage) {
+    let current = state(account);
+    // Shards report being disconnected after they were shut down on purpose
+    if current == ConnectionState::Disconnected {
+        return;
+    }
+
+    let new = match stage {
+        ConnectionStage::Connecting | ConnectionStage::Handshake => match current {
+            ConnectionState::Connecting | ConnectionState::Identifying => {
+                ConnectionState::Connecting
+            },
+            _ => ConnectionState::Reconnecting,
+        },
+        ConnectionStage::Identifying => ConnectionState::Identifying,
+        ConnectionStage::Resuming => ConnectionState::Resuming,
+        ConnectionStage::Disconnected => ConnectionState::Reconnecting,
+        // The ready or resumed event follows once the connection is usable
+        _ => return,
+    };
+    set_state(account, new);
+}
+
+/// The context of an account that is ready to be used, printing why it can't be otherwise
+///
+/// Commands use this instead of `get_ctx`, so they are refused with a message while the
+/// account is not connected.
+pub fn ready_ctx(account: &str) -> Option<Arc<Context>> {
+    let state = state(account);
+    match super::get_ctx(account) {
+        Some(ctx) if state.is_ready() => Some(ctx),
+        _ => {
+            print_not_ready(account, state);
+            None
+        },
+    }
+}
+
+/// Like `ready_ctx`, but also returns the context while a connected account is resuming or
+/// reconnecting, for actions that go through the send queue and can wait for the connection
+pub fn queue_ctx(account: &str) -> Option<Arc<Context>> {
+    let state = state(account);
+    match super::get_ctx(account) {
+        Some(ctx) if state != ConnectionState::Disconnected => Some(ctx),
+        _ => {
+            print_not_ready(account, state);
+            None
+        },
+    }
+}
+
+fn print_not_ready(account: &str, state: ConnectionState) {
+    let name = super::account_display_name(account);
+    if state == ConnectionState::Disconnected {
+        plugin_print(&format!(
+            "Not connected ({}), run /discord connect first",
+            name
+        ));
+    } else {
+        plugin_print(&format!(
+            "Still {} ({}), try again once connected",
+            state, name
+        ));
+    }
+}
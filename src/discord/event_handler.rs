@@ -1,9 +1,20 @@
 use crate::{
-    buffers, discord, on_main, on_main_blocking, utils, weechat_utils::MessageManager, Discord,
+    buffers,
+    discord::{
+        self,
+        connection::{self, ConnectionState},
+        mentions, threads,
+    },
+    on_main, on_main_blocking, utils,
+    utils::BufferExt,
+    weechat_utils::MessageManager,
+    Discord,
 };
 use lazy_static::lazy_static;
+use serde_json::{json, Value};
 use serenity::{
     cache::CacheRwLock,
+    client::bridge::gateway::event::ShardStageUpdateEvent,
     model::{gateway::Ready, prelude::*},
     prelude::*,
 };
@@ -57,15 +68,17 @@ lazy_static! {
 }
 
 pub struct Handler {
+    account: String,
     sender: Arc<Mutex<Sender<Ready>>>,
     watched_channels: Vec<utils::GuildOrChannel>,
 }
 
 impl Handler {
-    pub fn new(weecord: &Discord, sender: Arc<Mutex<Sender<Ready>>>) -> Handler {
+    pub fn new(weecord: &Discord, account: &str, sender: Arc<Mutex<Sender<Ready>>>) -> Handler {
         let watched_channels = weecord.config.watched_channels();
 
         Handler {
+            account: account.to_owned(),
             sender,
             watched_channels,
         }
@@ -76,6 +89,7 @@ impl EventHandler for Handler {
     fn channel_create(&self, _ctx: Context, channel: Arc<RwLock<GuildChannel>>) {
         let channel = channel.read();
         print_guild_status_message(
+            &self.account,
             channel.guild_id,
             &format!(
                 "New {} channel `{}` created",
@@ -88,13 +102,14 @@ impl EventHandler for Handler {
     fn channel_delete(&self, _ctx: Context, channel: Arc<RwLock<GuildChannel>>) {
         let channel = channel.read();
         print_guild_status_message(
+            &self.account,
             channel.guild_id,
             &format!("Channel `{}` deleted", channel.name()),
         );
     }
 
     fn channel_pins_update(&self, _ctx: Context, pin: ChannelPinsUpdateEvent) {
-        buffers::load_pin_buffer_history_for_id(pin.channel_id);
+        buffers::load_pin_buffer_history_for_id(&self.account, pin.channel_id);
     }
 
     fn channel_update(&self, ctx: Context, old: Option<Channel>, new: Channel) {
@@ -117,6 +132,7 @@ impl EventHandler for Handler {
                     if let Some(guild_id) = guild_id {
                         if new.name != old.name {
                             print_guild_status_message(
+                                &self.account,
                                 guild_id,
                                 &format!("Category `{}` renamed to `{}`", old.name, new.name),
                             );
@@ -131,6 +147,7 @@ impl EventHandler for Handler {
 
                     if new.name != old.name {
                         print_guild_status_message(
+                            &self.account,
                             new.guild_id,
                             &format!("Category `{}` renamed to `{}`", old.name, new.name),
                         );
@@ -142,10 +159,11 @@ impl EventHandler for Handler {
     }
 
     fn guild_member_update(&self, ctx: Context, old: Option<Member>, new: Member) {
+        let account = self.account.clone();
         thread::spawn(move || {
-            buffers::update_member_nick(&old, &new);
+            buffers::update_member_nick(&account, &old, &new);
             if ctx.cache.read().user.id == new.user_id() {
-                buffers::update_nick();
+                buffers::update_nick(&account);
             }
         });
     }
@@ -157,12 +175,18 @@ impl EventHandler for Handler {
         _offline_members: HashMap<UserId, Member>,
         nonce: Option<String>,
     ) {
+        let account = self.account.clone();
         on_main(move |weecord| {
             if let Some(channel_id) = nonce {
                 if let Ok(channel_id) = channel_id.parse::<u64>().map(|id| ChannelId(id)) {
-                    if let Some(buffer) = weecord
-                        .buffer_manager
-                        .get_buffer(&utils::buffer_id_for_channel(Some(guild_id), channel_id))
+                    if let Some(buffer) =
+                        weecord
+                            .buffer_manager
+                            .get_buffer(&utils::buffer_id_for_channel(
+                                &account,
+                                Some(guild_id),
+                                channel_id,
+                            ))
                     {
                         buffer.redraw_buffer(&ctx.cache);
                     }
@@ -172,10 +196,28 @@ impl EventHandler for Handler {
     }
 
     fn message(&self, ctx: Context, msg: Message) {
-        let string_channel = utils::buffer_id_for_channel(msg.guild_id, msg.channel_id);
+        let account = &self.account;
+        let string_channel = utils::buffer_id_for_channel(account, msg.guild_id, msg.channel_id);
+        // Serenity does not know about threads, so unknown guild channels are fetched here
+        // instead of while the main thread is blocked
+        if msg.guild_id.is_some()
+            && threads::get(account, msg.channel_id).is_none()
+            && msg.channel_id.to_channel_cached(&ctx.cache).is_none()
+        {
+            threads::fetch(account, msg.channel_id);
+        }
         let () = on_main_blocking(move |weecord| {
+            if !msg.is_own(&ctx.cache)
+                && msg.mentions_user_id(ctx.cache.read().user.id)
+                && mentions::add(account, msg.channel_id)
+            {
+                weecord.update_bar_item("discord_status");
+            }
+
             if let Some(buffer) = weecord.buffer_manager.get_buffer(&string_channel) {
                 print_message(&ctx.cache, &msg, &buffer);
+            } else if let Some(thread) = threads::get(account, msg.channel_id) {
+                print_thread_message(&ctx, weecord, account, &thread, &msg);
             } else {
                 match msg.channel_id.to_channel(&ctx) {
                     chan @ Ok(Channel::Private(_)) => {
@@ -185,6 +227,7 @@ impl EventHandler for Handler {
                             buffers::create_buffer_from_dm(
                                 &ctx.cache,
                                 &weecord,
+                                account,
                                 chan.unwrap(),
                                 &ctx.cache.read().user.name,
                                 false,
@@ -198,6 +241,7 @@ impl EventHandler for Handler {
                             buffers::create_buffer_from_group(
                                 &ctx.cache,
                                 &weecord,
+                                account,
                                 chan.unwrap(),
                                 &ctx.cache.read().user.name,
                             );
@@ -231,10 +275,11 @@ impl EventHandler for Handler {
                                     format!("@{}", current_user.name)
                                 };
 
-                                buffers::create_guild_buffer(guild.id, &guild.name);
+                                buffers::create_guild_buffer(account, guild.id, &guild.name);
                                 // TODO: Muting
                                 buffers::create_buffer_from_channel(
                                     &ctx.cache,
+                                    account,
                                     &guild.name,
                                     &channel,
                                     &nick,
@@ -251,7 +296,7 @@ impl EventHandler for Handler {
     }
 
     fn message_delete(&self, ctx: Context, channel_id: ChannelId, deleted_message_id: MessageId) {
-        delete_message(&ctx, channel_id, deleted_message_id)
+        delete_messages(&self.account, &ctx, channel_id, vec![deleted_message_id])
     }
 
     fn message_delete_bulk(
@@ -260,9 +305,7 @@ impl EventHandler for Handler {
         channel_id: ChannelId,
         deleted_messages_ids: Vec<MessageId>,
     ) {
-        for message_id in deleted_messages_ids {
-            delete_message(&ctx, channel_id, message_id)
-        }
+        delete_messages(&self.account, &ctx, channel_id, deleted_messages_ids)
     }
 
     fn message_update(
@@ -272,38 +315,19 @@ impl EventHandler for Handler {
         _new: Option<Message>,
         event: MessageUpdateEvent,
     ) {
-        let (guild_id, channel_id, message_id) = match ctx.cache.read().channel(&event.channel_id) {
-            Some(Channel::Guild(channel)) => {
-                let channel = channel.read();
-                (Some(channel.guild_id), channel.id, event.id)
-            },
-            Some(Channel::Group(channel)) => (None, channel.read().channel_id, event.id),
-            Some(Channel::Private(channel)) => (None, channel.read().id, event.id),
-            _ => return,
-        };
+        refresh_message(&self.account, ctx, event.channel_id, event.id);
+    }
 
-        let buffer_name = utils::buffer_id_for_channel(guild_id, channel_id);
+    fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        count_reaction(&self.account, &ctx, reaction, true);
+    }
 
-        thread::spawn(move || {
-            on_main(move |weecord| {
-                let ctx = match crate::discord::get_ctx() {
-                    Some(ctx) => ctx,
-                    _ => return,
-                };
-                let msg = match channel_id
-                    .messages(ctx, |retriever| retriever.limit(1).around(message_id))
-                    .ok()
-                    .and_then(|mut msgs| msgs.pop())
-                {
-                    Some(msgs) => msgs,
-                    None => return,
-                };
+    fn reaction_remove(&self, ctx: Context, reaction: Reaction) {
+        count_reaction(&self.account, &ctx, reaction, false);
+    }
 
-                if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
-                    buffer.replace_message(&ctx.cache, &message_id, &msg);
-                }
-            });
-        });
+    fn reaction_remove_all(&self, ctx: Context, channel_id: ChannelId, message_id: MessageId) {
+        update_reactions(&self.account, &ctx, channel_id, message_id, Vec::clear);
     }
 
     fn ready(&self, ctx: Context, ready: Ready) {
@@ -335,12 +359,20 @@ impl EventHandler for Handler {
             *crate::command::LAST_STATUS.lock() = presence.status;
         }
 
-        unsafe {
-            crate::discord::CONTEXT = Some(ctx);
-        }
+        discord::set_ctx(&self.account, ctx);
+        connection::set_state(&self.account, ConnectionState::Ready);
+        connection::set_session_id(&self.account, &ready.session_id);
         let _ = self.sender.lock().send(ready);
     }
 
+    fn resume(&self, _ctx: Context, _: ResumedEvent) {
+        connection::set_state(&self.account, ConnectionState::Ready);
+    }
+
+    fn shard_stage_update(&self, _ctx: Context, event: ShardStageUpdateEvent) {
+        connection::stage_changed(&self.account, event.new);
+    }
+
     fn typing_start(&self, ctx: Context, event: TypingStartEvent) {
         // TODO: Do we want to fetch the user if it isn't cached? (check performance)
         let current_user_id = ctx.cache.read().user.id;
@@ -385,40 +417,258 @@ impl EventHandler for Handler {
         }
     }
 
+    // Thread events are not supported by serenity
+    fn unknown(&self, ctx: Context, name: String, raw: Value) {
+        match name.as_str() {
+            "THREAD_CREATE" => {
+                let thread = match threads::Thread::from_value(&raw) {
+                    Some(thread) => thread,
+                    None => return,
+                };
+                threads::insert(&self.account, thread.clone());
+
+                if raw["newly_created"] == true {
+                    let parent_buffer = utils::buffer_id_for_channel(
+                        &self.account,
+                        Some(thread.guild_id),
+                        thread.parent_id,
+                    );
+                    let owner = raw["owner_id"]
+                        .as_str()
+                        .and_then(|id| id.parse().ok())
+                        .map(UserId);
+                    on_main(move |weecord| {
+                        if let Some(buffer) = weecord.buffer_manager.get_buffer(&parent_buffer) {
+                            let owner = owner
+                                .and_then(|owner| owner.to_user_cached(&ctx.cache))
+                                .map(|owner| owner.read().name.clone())
+                                .unwrap_or_else(|| "Someone".to_owned());
+                            buffer.print(&format!(
+                                "{}\t{} started a thread: {}",
+                                weecord.get_prefix("network"),
+                                owner,
+                                thread.name
+                            ));
+                        }
+                    });
+                }
+            },
+            "THREAD_UPDATE" => {
+                if let Some(thread) = threads::Thread::from_value(&raw) {
+                    threads::insert(&self.account, thread);
+                }
+            },
+            "THREAD_DELETE" => {
+                let id = match raw["id"].as_str().and_then(|id| id.parse().ok()) {
+                    Some(id) => ChannelId(id),
+                    None => return,
+                };
+                if let Some(thread) = threads::remove(&self.account, id) {
+                    let buffer_name =
+                        utils::buffer_id_for_channel(&self.account, Some(thread.guild_id), id);
+                    on_main(move |weecord| {
+                        if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                            buffer.print(&format!(
+                                "{}\tThis thread has been deleted",
+                                weecord.get_prefix("network"),
+                            ));
+                        }
+                    });
+                }
+            },
+            "THREAD_LIST_SYNC" => {
+                if let Some(list) = raw["threads"].as_array() {
+                    for thread in list.iter().filter_map(threads::Thread::from_value) {
+                        threads::insert(&self.account, thread);
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
     fn user_update(&self, _ctx: Context, _old: CurrentUser, _new: CurrentUser) {
-        thread::spawn(|| {
+        let account = self.account.clone();
+        thread::spawn(move || {
             // TODO: Update nicklist (and/or just rework all nick stuff)
-            buffers::update_nick();
+            buffers::update_nick(&account);
         });
     }
 }
 
-fn delete_message(ctx: &Context, channel_id: ChannelId, deleted_message_id: MessageId) {
-    if let Some(channel) = ctx.cache.read().channels.get(&channel_id) {
-        let guild_id = channel.read().guild_id;
-        let buffer_name = utils::buffer_id_for_channel(Some(guild_id), channel_id);
+fn delete_messages(
+    account: &str,
+    ctx: &Context,
+    channel_id: ChannelId,
+    deleted_message_ids: Vec<MessageId>,
+) {
+    let guild_id = match ctx.cache.read().channels.get(&channel_id) {
+        Some(channel) => Some(channel.read().guild_id),
+        None => threads::get(account, channel_id).map(|thread| thread.guild_id),
+    };
+    if let Some(guild_id) = guild_id {
+        let buffer_name = utils::buffer_id_for_channel(account, Some(guild_id), channel_id);
+        let ctx = ctx.clone();
 
         on_main(move |weecord| {
             if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
-                let ctx = match discord::get_ctx() {
-                    Some(ctx) => ctx,
-                    _ => return,
-                };
+                if let [deleted_message_id] = deleted_message_ids[..] {
+                    buffer.delete_message(&ctx.cache, &deleted_message_id);
+                } else {
+                    buffer.delete_messages(&ctx.cache, &deleted_message_ids);
+                }
+            }
+        });
+    }
+}
 
-                buffer.delete_message(&ctx.cache, &deleted_message_id);
+/// The name of the buffer the messages of a channel are printed in
+fn channel_buffer(account: &str, ctx: &Context, channel_id: ChannelId) -> Option<String> {
+    let guild_id = match ctx.cache.read().channel(&channel_id) {
+        Some(Channel::Guild(channel)) => Some(channel.read().guild_id),
+        Some(Channel::Group(_)) | Some(Channel::Private(_)) => None,
+        None => Some(threads::get(account, channel_id)?.guild_id),
+        _ => return None,
+    };
+    Some(utils::buffer_id_for_channel(account, guild_id, channel_id))
+}
+
+/// Fetch the latest version of a message and redraw it, if it has been printed to a buffer
+fn refresh_message(account: &str, ctx: Context, channel_id: ChannelId, message_id: MessageId) {
+    let buffer_name = match channel_buffer(account, &ctx, channel_id) {
+        Some(buffer_name) => buffer_name,
+        None => return,
+    };
+    let shown: bool = on_main_blocking(|weecord| {
+        weecord
+            .buffer_manager
+            .get_buffer(&buffer_name)
+            .map_or(false, |buffer| buffer.get_message(&message_id).is_some())
+    });
+    if !shown {
+        return;
+    }
+
+    thread::spawn(move || {
+        let msg = match channel_id
+            .messages(&ctx, |retriever| retriever.limit(1).around(message_id))
+            .ok()
+            .and_then(|mut msgs| msgs.pop())
+        {
+            Some(msg) => msg,
+            None => return,
+        };
+
+        on_main(move |weecord| {
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                buffer.replace_message(&ctx.cache, &message_id, &msg);
             }
         });
+    });
+}
+
+/// Count a reaction that was added or removed, the message is not fetched again for this
+fn count_reaction(account: &str, ctx: &Context, reaction: Reaction, added: bool) {
+    let own = reaction.user_id == ctx.cache.read().user.id;
+    let emoji = reaction.emoji;
+    let update = move |reactions: &mut Vec<MessageReaction>| {
+        match reactions
+            .iter()
+            .position(|it| same_reaction(&it.reaction_type, &emoji))
+        {
+            Some(index) if added => {
+                reactions[index].count += 1;
+                reactions[index].me |= own;
+            },
+            Some(index) => {
+                let reaction = &mut reactions[index];
+                reaction.count = reaction.count.saturating_sub(1);
+                if own {
+                    reaction.me = false;
+                }
+                if reaction.count == 0 {
+                    reactions.remove(index);
+                }
+            },
+            None if added => {
+                // Reactions can only be created by deserializing them
+                let reaction = json!({ "count": 1, "me": own, "emoji": emoji });
+                if let Ok(reaction) = serde_json::from_value(reaction) {
+                    reactions.push(reaction);
+                }
+            },
+            None => {},
+        }
+    };
+    update_reactions(
+        account,
+        ctx,
+        reaction.channel_id,
+        reaction.message_id,
+        update,
+    );
+}
+
+/// Custom emojis are compared by id, as their name is not always included
+fn same_reaction(a: &ReactionType, b: &ReactionType) -> bool {
+    match (a, b) {
+        (ReactionType::Custom { id: a, .. }, ReactionType::Custom { id: b, .. }) => a == b,
+        (ReactionType::Unicode(a), ReactionType::Unicode(b)) => a == b,
+        _ => false,
     }
 }
 
+fn update_reactions(
+    account: &str,
+    ctx: &Context,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    update: impl FnOnce(&mut Vec<MessageReaction>) + Send + 'static,
+) {
+    let buffer_name = match channel_buffer(account, ctx, channel_id) {
+        Some(buffer_name) => buffer_name,
+        None => return,
+    };
+    let ctx = ctx.clone();
+    on_main(move |weecord| {
+        if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+            buffer.update_reactions(&ctx.cache, &message_id, update);
+        }
+    });
+}
+
 fn print_message(cache: &CacheRwLock, msg: &Message, buffer: &MessageManager) {
     let muted = utils::buffer_is_muted(&buffer);
     let notify = !msg.is_own(cache) && !muted;
+    // The message will be loaded once the buffer is showing the latest messages again
+    if buffer.detached() {
+        buffer.add_unseen_message(cache, &msg, notify);
+        return;
+    }
     buffer.add_message(cache, &msg, notify);
 }
 
-fn print_guild_status_message(guild_id: GuildId, msg: &str) {
-    let buffer_id = utils::buffer_id_for_guild(guild_id);
+/// Print a message in a thread, opening the thread buffer if its parent channel is open
+fn print_thread_message(
+    ctx: &Context,
+    weecord: &Discord,
+    account: &str,
+    thread: &threads::Thread,
+    msg: &Message,
+) {
+    let parent_buffer =
+        utils::buffer_id_for_channel(account, Some(thread.guild_id), thread.parent_id);
+    if weecord.buffer_manager.get_buffer(&parent_buffer).is_none() {
+        return;
+    }
+
+    let nick = format!("@{}", ctx.cache.read().user.name);
+    let buffer = buffers::create_buffer_from_thread(&ctx.cache, weecord, account, thread, &nick);
+    print_message(&ctx.cache, msg, &buffer);
+}
+
+fn print_guild_status_message(account: &str, guild_id: GuildId, msg: &str) {
+    let buffer_id = utils::buffer_id_for_guild(account, guild_id);
 
     let msg = msg.to_owned();
     on_main(move |weechat| {
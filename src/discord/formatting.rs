@@ -1,65 +1,259 @@
-use parsing::{self, MarkdownNode};
-use std::{rc::Rc, sync::RwLock};
-use weechat::Weechat;
+use crate::{
+    discord::{highlight, threads},
+    utils,
+    utils::ChannelExt,
+};
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use parsing::{self, Block, ListMarker, MarkdownNode, Mention};
+use regex::Regex;
+use serenity::{cache::CacheRwLock, model::prelude::*};
+use std::{borrow::Cow, mem, rc::Rc, sync::RwLock};
+use weechat::{ConfigOption, Weechat};
 
-pub fn discord_to_weechat(weechat: &Weechat, msg: &str) -> String {
-    let ast = parsing::parse_markdown(msg);
+/// Render discord markdown with weechat colors, spoilers are masked unless `show_spoilers` is set
+///
+/// `mentions` renders the mentions in text, it is not called for code.
+pub fn discord_to_weechat(
+    weechat: &Weechat,
+    msg: &str,
+    show_spoilers: bool,
+    mentions: &dyn Fn(&str) -> String,
+) -> String {
+    let render = |text: &str| render_inline(weechat, text, show_spoilers, mentions);
+    let reset = weechat.color("reset");
 
-    let mut out = String::new();
-    for node in &ast.0 {
-        out.push_str(&discord_to_weechat_reducer(
-            &weechat,
-            &*node.read().unwrap(),
-        ))
+    parsing::parse_blocks(msg)
+        .into_iter()
+        .map(|block| match block {
+            Block::Header { level: 1, text } => format!(
+                "{}{}{}{}",
+                weechat.color("bold"),
+                weechat.color("underline"),
+                render(text),
+                reset
+            ),
+            Block::Header { text, .. } => {
+                format!("{}{}{}", weechat.color("bold"), render(text), reset)
+            },
+            Block::Subtext(text) => format!("{}{}{}", weechat.color("8"), render(text), reset),
+            Block::ListItem {
+                indent,
+                marker,
+                text,
+            } => {
+                let marker = match marker {
+                    ListMarker::Bullet => if indent == 0 { "•" } else { "◦" }.to_owned(),
+                    ListMarker::Number(n) => format!("{}.", n),
+                };
+                format!("{}{} {}", "  ".repeat(indent), marker, render(text))
+            },
+            // Block quotes end with a newline, which is already added between blocks
+            Block::Text(text) => render(text).trim_end_matches('\n').to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Replace the spoilers of text that is shown without rendering its markdown with
+/// `weecord.look.spoiler_mask`
+pub fn mask_spoilers<'a>(weechat: &Weechat, text: &'a str) -> Cow<'a, str> {
+    lazy_static! {
+        static ref SPOILER: Regex = Regex::new(r"\|\|(?s:.+?)\|\|").unwrap();
     }
-    out
+
+    let mask = crate::upgrade_plugin(weechat).config.spoiler_mask.value();
+    SPOILER.replace_all(text, regex::NoExpand(&mask))
+}
+
+fn render_inline(
+    weechat: &Weechat,
+    text: &str,
+    show_spoilers: bool,
+    mentions: &dyn Fn(&str) -> String,
+) -> String {
+    // The url is always shown, so that a link can't pretend to go somewhere else
+    let text = parsing::replace_masked_links(text, |text, url| {
+        format!(
+            "{}{}{} {}<{}>{}",
+            weechat.color("underline"),
+            text,
+            weechat.color("-underline"),
+            weechat.color("8"),
+            url,
+            weechat.color("reset")
+        )
+    });
+    let ast = parsing::parse_markdown(&text);
+    collect_styles(weechat, &ast.0, show_spoilers, mentions)
+}
+
+/// Render timestamps and channel, role and slash command mentions
+pub fn render_mentions(
+    cache: &CacheRwLock,
+    weechat: &Weechat,
+    account: &str,
+    guild: Option<GuildId>,
+    msg: &str,
+) -> String {
+    parsing::replace_mentions(msg, |mention| match mention {
+        Mention::Timestamp { unix, style } => Some(format!(
+            "{}{}{}",
+            weechat.color("cyan"),
+            format_timestamp(unix, style)?,
+            weechat.color("reset")
+        )),
+        Mention::Channel(id) => {
+            let name = ChannelId(id)
+                .to_channel_cached(cache)
+                .map(|channel| channel.name())
+                .or_else(|| threads::get(account, ChannelId(id)).map(|thread| thread.name))
+                .unwrap_or_else(|| "unknown-channel".to_owned());
+            Some(format!(
+                "{}#{}{}",
+                weechat.color("chat_channel"),
+                name,
+                weechat.color("reset")
+            ))
+        },
+        Mention::Role(id) => {
+            let role = guild
+                .and_then(|guild| cache.read().guild(&guild))
+                .and_then(|guild| guild.read().roles.get(&RoleId(id)).cloned());
+            Some(match role {
+                // Roles without a color use the default text color
+                Some(role) if role.colour.0 != 0 => format!(
+                    "{}@{}{}",
+                    weechat.color(&utils::rgb_to_ansi(role.colour).to_string()),
+                    role.name,
+                    weechat.color("reset")
+                ),
+                Some(role) => format!("@{}", role.name),
+                None => "@deleted-role".to_owned(),
+            })
+        },
+        Mention::SlashCommand { name, .. } => Some(format!(
+            "{}/{}{}",
+            weechat.color("blue"),
+            name,
+            weechat.color("reset")
+        )),
+    })
+}
+
+/// Format a timestamp in the local time zone, using one of Discord's timestamp styles
+fn format_timestamp(unix: i64, style: char) -> Option<String> {
+    let time = Local.timestamp_opt(unix, 0).single()?;
+    let format = match style {
+        't' => "%H:%M",
+        'T' => "%H:%M:%S",
+        'd' => "%d/%m/%Y",
+        'D' => "%-d %B %Y",
+        'F' => "%A, %-d %B %Y %H:%M",
+        'R' => return Some(format_relative(unix - Local::now().timestamp())),
+        _ => "%-d %B %Y %H:%M",
+    };
+    Some(time.format(format).to_string())
 }
 
-fn collect_styles(weechat: &Weechat, styles: &[Rc<RwLock<MarkdownNode>>]) -> String {
-    styles
+/// Format a difference in seconds like "in 5 minutes" or "3 days ago"
+fn format_relative(seconds: i64) -> String {
+    const UNITS: &[(i64, &str)] = &[
+        (60 * 60 * 24 * 365, "year"),
+        (60 * 60 * 24 * 30, "month"),
+        (60 * 60 * 24, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+        (1, "second"),
+    ];
+
+    let (size, unit) = UNITS
         .iter()
-        .map(|s| discord_to_weechat_reducer(&weechat, &*s.read().unwrap()))
-        .collect::<Vec<_>>()
-        .join("")
+        .find(|(size, _)| seconds.abs() >= *size)
+        .unwrap_or(&(1, "second"));
+    let count = seconds.abs() / size;
+    let plural = if count == 1 { "" } else { "s" };
+
+    if seconds < 0 {
+        format!("{} {}{} ago", count, unit, plural)
+    } else {
+        format!("in {} {}{}", count, unit, plural)
+    }
 }
 
-// TODO: Spoilers, code syntax highlighting?
-// TODO: if the whole line is wrapped in *, render as CTCP ACTION rather than
-// as fully italicized message.
-fn discord_to_weechat_reducer(weechat: &Weechat, node: &MarkdownNode) -> String {
+/// Render a list of nodes, consecutive text nodes are joined first because the parser splits
+/// text at punctuation, which would split mentions
+fn collect_styles(
+    weechat: &Weechat,
+    styles: &[Rc<RwLock<MarkdownNode>>],
+    show_spoilers: bool,
+    mentions: &dyn Fn(&str) -> String,
+) -> String {
+    let mut out = String::new();
+    let mut text = String::new();
+    for style in styles {
+        let node = style.read().unwrap();
+        if let MarkdownNode::Text(string) = &*node {
+            text.push_str(string);
+            continue;
+        }
+        out.push_str(&mentions(&mem::take(&mut text)));
+        out.push_str(&discord_to_weechat_reducer(
+            weechat,
+            &node,
+            show_spoilers,
+            mentions,
+        ));
+    }
+    out.push_str(&mentions(&text));
+    out
+}
+
+fn discord_to_weechat_reducer(
+    weechat: &Weechat,
+    node: &MarkdownNode,
+    show_spoilers: bool,
+    mentions: &dyn Fn(&str) -> String,
+) -> String {
     use MarkdownNode::*;
     match node {
         Bold(styles) => format!(
             "{}{}{}",
             weechat.color("bold"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, styles, show_spoilers, mentions),
             weechat.color("-bold")
         ),
         Italic(styles) => format!(
             "{}{}{}",
             weechat.color("italic"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, styles, show_spoilers, mentions),
             weechat.color("-italic")
         ),
         Underline(styles) => format!(
             "{}{}{}",
             weechat.color("underline"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, styles, show_spoilers, mentions),
             weechat.color("-underline")
         ),
         Strikethrough(styles) => format!(
             "{}~~{}~~{}",
             weechat.color("red"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, styles, show_spoilers, mentions),
             weechat.color("-red")
         ),
-        Spoiler(styles) => format!(
+        Spoiler(styles) if show_spoilers => format!(
             "{}||{}||{}",
             weechat.color("italic"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, styles, show_spoilers, mentions),
             weechat.color("-italic")
         ),
-        Text(string) => string.to_owned(),
+        Spoiler(_) => format!(
+            "{}{}{}",
+            weechat.color("darkgray"),
+            crate::upgrade_plugin(weechat).config.spoiler_mask.value(),
+            weechat.color("reset")
+        ),
+        Text(string) => mentions(string),
         InlineCode(string) => format!(
             "{}{}{}",
             weechat.color("*8"),
@@ -67,20 +261,19 @@ fn discord_to_weechat_reducer(weechat: &Weechat, node: &MarkdownNode) -> String
             weechat.color("reset")
         ),
         Code(language, text) => {
-            let (fmt, reset) = (weechat.color("*8"), weechat.color("reset"));
+            let theme = crate::upgrade_plugin(weechat).config.code_theme();
 
             format!(
                 "```{}\n{}\n```",
                 language,
-                text.lines()
-                    .map(|l| format!("{}{}{}", fmt, l, reset))
-                    .collect::<Vec<_>>()
-                    .join("\n"),
+                highlight::highlight(weechat, &theme, language, text).join("\n"),
             )
         },
-        BlockQuote(styles) => format_block_quote(collect_styles(weechat, styles).lines()),
+        BlockQuote(styles) => {
+            format_block_quote(collect_styles(weechat, styles, show_spoilers, mentions).lines())
+        },
         SingleBlockQuote(styles) => format_block_quote(
-            collect_styles(weechat, styles)
+            collect_styles(weechat, styles, show_spoilers, mentions)
                 .lines()
                 .map(strip_leading_bracket),
         ),
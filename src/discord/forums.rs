@@ -0,0 +1,181 @@
+//! Forum channels are not supported by serenity, their posts are threads and are managed through
+//! the http api
+
+use super::{
+    http::{self, Method},
+    threads::{self, parse_id, Thread},
+};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use serenity::model::prelude::*;
+use std::{collections::HashMap, sync::Arc};
+
+const GUILD_FORUM: u64 = 15;
+
+#[derive(Debug, Clone)]
+pub struct Forum {
+    pub id: ChannelId,
+    pub guild_id: GuildId,
+    pub name: String,
+    pub topic: Option<String>,
+    /// The tags that can be applied to posts, by id
+    pub tags: HashMap<u64, String>,
+}
+
+impl Forum {
+    /// Parse a forum from a raw channel object, returns `None` if the channel is not a forum
+    pub fn from_value(value: &Value) -> Option<Forum> {
+        if value["type"].as_u64()? != GUILD_FORUM {
+            return None;
+        }
+
+        let tags = value["available_tags"]
+            .as_array()
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(|tag| {
+                        Some((parse_id(&tag["id"])?, tag["name"].as_str()?.to_owned()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Forum {
+            id: ChannelId(parse_id(&value["id"])?),
+            guild_id: GuildId(parse_id(&value["guild_id"])?),
+            name: value["name"].as_str()?.to_owned(),
+            topic: value["topic"].as_str().map(ToOwned::to_owned),
+            tags,
+        })
+    }
+
+    /// The names of the tags applied to a post
+    pub fn tag_names(&self, post: &Thread) -> Vec<&str> {
+        post.applied_tags
+            .iter()
+            .filter_map(|tag| self.tags.get(tag))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+lazy_static! {
+    // Forums are keyed by account like threads
+    static ref FORUMS: Arc<Mutex<HashMap<(String, ChannelId), Forum>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    /// The posts listed in each forum index buffer, in the order they are numbered
+    static ref INDEXES: Arc<Mutex<HashMap<(String, ChannelId), Vec<ChannelId>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Get a known forum of an account
+pub fn get(account: &str, id: ChannelId) -> Option<Forum> {
+    FORUMS.lock().get(&(account.to_owned(), id)).cloned()
+}
+
+/// Find a known forum of a guild by name or id
+pub fn find(account: &str, guild: GuildId, name: &str) -> Option<Forum> {
+    FORUMS
+        .lock()
+        .iter()
+        .find(|((forum_account, _), forum)| {
+            forum_account == account
+                && forum.guild_id == guild
+                && (parsing::weechat_arg_strip(&forum.name).to_lowercase() == name.to_lowercase()
+                    || forum.id.0.to_string() == name)
+        })
+        .map(|(_, forum)| forum.clone())
+}
+
+/// Fetch the forums of a guild, and remember them
+pub fn fetch_guild_forums(account: &str, guild: GuildId) -> Vec<Forum> {
+    let channels = match http::get(account, &format!("/guilds/{}/channels", guild.0), &[]) {
+        Ok(Value::Array(channels)) => channels,
+        _ => return Vec::new(),
+    };
+
+    let mut forums: Vec<_> = channels.iter().filter_map(Forum::from_value).collect();
+    forums.sort_by_key(|forum| forum.name.clone());
+
+    let mut known = FORUMS.lock();
+    for forum in &forums {
+        known.insert((account.to_owned(), forum.id), forum.clone());
+    }
+    forums
+}
+
+/// Fetch the active and recently archived posts of a forum, most recently active first
+///
+/// This blocks until the requests complete, so it must not be called on the main thread
+pub fn posts(account: &str, forum: &Forum) -> Result<Vec<Thread>, http::Error> {
+    let active = http::get(
+        account,
+        &format!("/guilds/{}/threads/active", forum.guild_id.0),
+        &[],
+    )?;
+    let archived = http::get(
+        account,
+        &format!("/channels/{}/threads/archived/public", forum.id.0),
+        &[],
+    )?;
+
+    let mut posts: Vec<_> = active["threads"]
+        .as_array()
+        .into_iter()
+        .chain(archived["threads"].as_array())
+        .flatten()
+        .filter_map(Thread::from_value)
+        .filter(|thread| thread.parent_id == forum.id)
+        .collect();
+    posts.sort_by(|a, b| {
+        b.last_message_id
+            .unwrap_or(MessageId(b.id.0))
+            .cmp(&a.last_message_id.unwrap_or(MessageId(a.id.0)))
+    });
+    posts.dedup_by_key(|post| post.id);
+
+    for post in &posts {
+        threads::insert(account, post.clone());
+    }
+    INDEXES.lock().insert(
+        (account.to_owned(), forum.id),
+        posts.iter().map(|post| post.id).collect(),
+    );
+
+    Ok(posts)
+}
+
+/// Get the nth (starting at 1) post listed in a forum index
+pub fn nth_post(account: &str, forum: ChannelId, n: usize) -> Option<Thread> {
+    let id = *INDEXES
+        .lock()
+        .get(&(account.to_owned(), forum))?
+        .get(n.checked_sub(1)?)?;
+    threads::get(account, id)
+}
+
+/// Create a new post in a forum
+pub fn create_post(
+    account: &str,
+    forum: ChannelId,
+    title: &str,
+    content: &str,
+) -> Result<Thread, http::Error> {
+    let body = json!({
+        "name": title,
+        "message": { "content": content },
+    });
+    let value = http::request(
+        account,
+        Method::Post,
+        &format!("/channels/{}/threads", forum.0),
+        &[],
+        Some(&body),
+    )?;
+
+    let post = Thread::from_value(&value)
+        .ok_or_else(|| http::Error::Api("response was not a forum post".to_owned()))?;
+    threads::insert(account, post.clone());
+    Ok(post)
+}
@@ -0,0 +1,457 @@
+//! A small syntax highlighter for fenced code blocks
+//!
+//! This only recognizes keywords, strings, comments and numbers, which is enough to make code
+//! readable without pulling in a full grammar for every language.
+
+use weechat::Weechat;
+
+/// The builtin themes, see `weecord.look.code_theme`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeTheme {
+    Dark,
+    Light,
+    None,
+}
+
+/// The weechat colors used for each kind of token
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub plain: String,
+    pub keyword: String,
+    pub string: String,
+    pub comment: String,
+    pub number: String,
+    pub added: String,
+    pub removed: String,
+    pub hunk: String,
+}
+
+impl Theme {
+    /// Get a builtin theme with colors overridden by a list of `token:color` pairs, along with the
+    /// overrides that were not recognized
+    pub fn new(base: CodeTheme, overrides: &str) -> (Theme, Vec<String>) {
+        let (plain, keyword, string, comment, number) = match base {
+            CodeTheme::Dark => ("*8", "lightmagenta", "lightgreen", "darkgray", "lightcyan"),
+            CodeTheme::Light => ("black", "magenta", "green", "darkgray", "blue"),
+            CodeTheme::None => ("*8", "*8", "*8", "*8", "*8"),
+        };
+        let mut theme = Theme {
+            plain: plain.to_owned(),
+            keyword: keyword.to_owned(),
+            string: string.to_owned(),
+            comment: comment.to_owned(),
+            number: number.to_owned(),
+            added: "green".to_owned(),
+            removed: "red".to_owned(),
+            hunk: "cyan".to_owned(),
+        };
+        if base == CodeTheme::None {
+            theme.added = theme.plain.clone();
+            theme.removed = theme.plain.clone();
+            theme.hunk = theme.plain.clone();
+        }
+
+        let mut unknown = Vec::new();
+        for pair in overrides
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let mut parts = pair.splitn(2, ':');
+            let token = parts.next().unwrap_or_default().trim();
+            let color = match parts.next() {
+                Some(color) => color.trim().to_owned(),
+                None => {
+                    unknown.push(pair.to_owned());
+                    continue;
+                },
+            };
+            match token {
+                "plain" => theme.plain = color,
+                "keyword" => theme.keyword = color,
+                "string" => theme.string = color,
+                "comment" => theme.comment = color,
+                "number" => theme.number = color,
+                "added" => theme.added = color,
+                "removed" => theme.removed = color,
+                "hunk" => theme.hunk = color,
+                _ => unknown.push(pair.to_owned()),
+            }
+        }
+        (theme, unknown)
+    }
+}
+
+struct Language {
+    keywords: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "auto",
+    "break",
+    "case",
+    "char",
+    "class",
+    "const",
+    "continue",
+    "default",
+    "delete",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "false",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "include",
+    "inline",
+    "int",
+    "long",
+    "namespace",
+    "new",
+    "nullptr",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "template",
+    "this",
+    "true",
+    "typedef",
+    "typename",
+    "union",
+    "unsigned",
+    "using",
+    "virtual",
+    "void",
+    "volatile",
+    "while",
+];
+
+fn language(tag: &str) -> Option<Language> {
+    let language = match tag.to_lowercase().as_str() {
+        "rust" | "rs" => Language {
+            keywords: &[
+                "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else",
+                "enum", "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match",
+                "mod", "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct",
+                "super", "trait", "true", "type", "unsafe", "use", "where", "while",
+            ],
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"'],
+        },
+        "python" | "py" => Language {
+            keywords: &[
+                "and", "as", "assert", "async", "await", "break", "class", "continue", "def",
+                "del", "elif", "else", "except", "False", "finally", "for", "from", "global", "if",
+                "import", "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise",
+                "return", "self", "True", "try", "while", "with", "yield",
+            ],
+            line_comments: &["#"],
+            block_comment: None,
+            quotes: &['"', '\''],
+        },
+        "javascript" | "js" | "jsx" | "typescript" | "ts" | "tsx" => Language {
+            keywords: &[
+                "async",
+                "await",
+                "break",
+                "case",
+                "catch",
+                "class",
+                "const",
+                "continue",
+                "default",
+                "delete",
+                "do",
+                "else",
+                "export",
+                "extends",
+                "false",
+                "finally",
+                "for",
+                "from",
+                "function",
+                "if",
+                "import",
+                "in",
+                "instanceof",
+                "interface",
+                "let",
+                "new",
+                "null",
+                "of",
+                "return",
+                "static",
+                "super",
+                "switch",
+                "this",
+                "throw",
+                "true",
+                "try",
+                "type",
+                "typeof",
+                "undefined",
+                "var",
+                "void",
+                "while",
+                "yield",
+            ],
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"', '\'', '`'],
+        },
+        "json" => Language {
+            keywords: &["true", "false", "null"],
+            line_comments: &[],
+            block_comment: None,
+            quotes: &['"'],
+        },
+        "sh" | "bash" | "shell" | "zsh" | "console" => Language {
+            keywords: &[
+                "case", "do", "done", "echo", "elif", "else", "esac", "exit", "export", "fi",
+                "for", "function", "if", "in", "local", "return", "then", "until", "while",
+            ],
+            line_comments: &["#"],
+            block_comment: None,
+            quotes: &['"', '\''],
+        },
+        "c" | "h" | "cpp" | "c++" | "hpp" | "cc" | "cs" | "csharp" => Language {
+            keywords: C_KEYWORDS,
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"', '\''],
+        },
+        "go" | "golang" => Language {
+            keywords: &[
+                "break",
+                "case",
+                "chan",
+                "const",
+                "continue",
+                "default",
+                "defer",
+                "else",
+                "false",
+                "for",
+                "func",
+                "go",
+                "goto",
+                "if",
+                "import",
+                "interface",
+                "map",
+                "nil",
+                "package",
+                "range",
+                "return",
+                "select",
+                "struct",
+                "switch",
+                "true",
+                "type",
+                "var",
+            ],
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"', '\'', '`'],
+        },
+        "java" | "kotlin" | "kt" => Language {
+            keywords: &[
+                "abstract",
+                "boolean",
+                "break",
+                "case",
+                "catch",
+                "class",
+                "else",
+                "extends",
+                "false",
+                "final",
+                "finally",
+                "for",
+                "fun",
+                "if",
+                "implements",
+                "import",
+                "int",
+                "interface",
+                "new",
+                "null",
+                "package",
+                "private",
+                "protected",
+                "public",
+                "return",
+                "static",
+                "super",
+                "switch",
+                "this",
+                "throw",
+                "throws",
+                "true",
+                "try",
+                "val",
+                "var",
+                "void",
+                "when",
+                "while",
+            ],
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"', '\''],
+        },
+        "toml" | "yaml" | "yml" | "ini" => Language {
+            keywords: &["true", "false", "null"],
+            line_comments: &["#", ";"],
+            block_comment: None,
+            quotes: &['"', '\''],
+        },
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// Highlight a code block, returning the colored lines
+pub fn highlight(weechat: &Weechat, theme: &Theme, tag: &str, code: &str) -> Vec<String> {
+    let reset = weechat.color("reset");
+
+    match tag.to_lowercase().as_str() {
+        "diff" | "patch" => {
+            return code
+                .lines()
+                .map(|line| {
+                    let color = if line.starts_with("+++") || line.starts_with("---") {
+                        "bold"
+                    } else if line.starts_with('+') {
+                        &theme.added
+                    } else if line.starts_with('-') {
+                        &theme.removed
+                    } else if line.starts_with("@@") {
+                        &theme.hunk
+                    } else {
+                        &theme.plain
+                    };
+                    format!("{}{}{}", weechat.color(color), line, reset)
+                })
+                .collect();
+        },
+        _ => {},
+    }
+
+    let language = match language(tag) {
+        Some(language) => language,
+        None => {
+            let plain = weechat.color(&theme.plain);
+            return code
+                .lines()
+                .map(|line| format!("{}{}{}", plain, line, reset))
+                .collect();
+        },
+    };
+
+    let mut in_block_comment = false;
+    code.lines()
+        .map(|line| highlight_line(weechat, theme, &language, line, &mut in_block_comment))
+        .collect()
+}
+
+fn highlight_line(
+    weechat: &Weechat,
+    theme: &Theme,
+    language: &Language,
+    line: &str,
+    in_block_comment: &mut bool,
+) -> String {
+    let mut out = String::new();
+    let mut push = |color: &str, token: &str| {
+        out.push_str(&weechat.color(color));
+        out.push_str(token);
+        out.push_str(&weechat.color("reset"));
+    };
+
+    let mut rest = line;
+    while !rest.is_empty() {
+        if *in_block_comment {
+            let (_, end) = language.block_comment.unwrap();
+            match rest.find(end) {
+                Some(index) => {
+                    push(&theme.comment, &rest[..index + end.len()]);
+                    rest = &rest[index + end.len()..];
+                    *in_block_comment = false;
+                },
+                None => {
+                    push(&theme.comment, rest);
+                    rest = "";
+                },
+            }
+            continue;
+        }
+
+        if language
+            .line_comments
+            .iter()
+            .any(|prefix| rest.starts_with(prefix))
+        {
+            push(&theme.comment, rest);
+            break;
+        }
+        if let Some((start, _)) = language.block_comment {
+            if rest.starts_with(start) {
+                *in_block_comment = true;
+                push(&theme.comment, start);
+                rest = &rest[start.len()..];
+                continue;
+            }
+        }
+
+        let c = rest.chars().next().unwrap();
+        let len = if language.quotes.contains(&c) {
+            // Find the closing quote, skipping escaped characters
+            let mut escaped = false;
+            let end = rest[1..].find(|ch| {
+                let closes = ch == c && !escaped;
+                escaped = ch == '\\' && !escaped;
+                closes
+            });
+            let len = end.map_or(rest.len(), |end| end + 2);
+            push(&theme.string, &rest[..len]);
+            len
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|ch: char| !ch.is_ascii_alphanumeric() && ch != '.' && ch != '_')
+                .unwrap_or_else(|| rest.len());
+            push(&theme.number, &rest[..len]);
+            len
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|ch: char| !ch.is_alphanumeric() && ch != '_')
+                .unwrap_or_else(|| rest.len());
+            let word = &rest[..len];
+            if language.keywords.contains(&word) {
+                push(&theme.keyword, word);
+            } else {
+                push(&theme.plain, word);
+            }
+            len
+        } else {
+            push(&theme.plain, &rest[..c.len_utf8()]);
+            c.len_utf8()
+        };
+        rest = &rest[len..];
+    }
+    out
+}
@@ -0,0 +1,92 @@
+//! Requests to endpoints serenity has no methods for, like threads and search
+//!
+//! They are still made through serenity's http client, so they share its api version and wait
+//! for its rate limits like every other request.
+
+use serde_json::Value;
+use serenity::http::{request::RequestBuilder, routing::RouteInfo};
+use std::{borrow::Cow, fmt, io::Read};
+use url::form_urlencoded;
+
+pub use serenity::http::LightMethod as Method;
+
+#[derive(Debug)]
+pub enum Error {
+    Http(serenity::Error),
+    Json(serde_json::Error),
+    /// Discord accepted the request, but did not respond as expected
+    Api(String),
+    NotConnected,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "invalid response: {}", e),
+            Error::Api(message) => write!(f, "{}", message),
+            Error::NotConnected => write!(f, "not connected"),
+        }
+    }
+}
+
+impl From<serenity::Error> for Error {
+    fn from(e: serenity::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+/// Make a request to the api as `account`, returning the json response or `Value::Null` if there
+/// is no body
+///
+/// This blocks until the request completes, so it must not be called on the main thread
+pub fn request(
+    account: &str,
+    method: Method,
+    path: &str,
+    query: &[(&str, String)],
+    body: Option<&Value>,
+) -> Result<Value, Error> {
+    let ctx = super::get_ctx(account).ok_or(Error::NotConnected)?;
+
+    let mut path = path.to_owned();
+    if !query.is_empty() {
+        path.push('?');
+        path.push_str(
+            &form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(query)
+                .finish(),
+        );
+    }
+    let body = body.map(serde_json::to_vec).transpose()?;
+
+    let mut request = RequestBuilder::new(RouteInfo::Custom {
+        method,
+        path: Cow::from(path),
+    });
+    if let Some(body) = &body {
+        request.body(Some(body));
+    }
+    // Unsuccessful responses are turned into errors by serenity
+    let mut response = ctx.http.request(request.build())?;
+
+    let mut text = String::new();
+    response
+        .read_to_string(&mut text)
+        .map_err(serenity::Error::from)?;
+    if text.is_empty() {
+        Ok(Value::Null)
+    } else {
+        Ok(serde_json::from_str(&text)?)
+    }
+}
+
+pub fn get(account: &str, path: &str, query: &[(&str, String)]) -> Result<Value, Error> {
+    request(account, Method::Get, path, query, None)
+}
@@ -0,0 +1,43 @@
+//! Unread mentions of each account, shown by the `discord_status` bar item
+//!
+//! Mentions are counted until the buffer of their channel is switched to.
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serenity::model::id::ChannelId;
+use std::{collections::HashMap, sync::Arc};
+
+lazy_static! {
+    static ref UNREAD: Arc<Mutex<HashMap<(String, ChannelId), usize>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    static ref CURRENT: Arc<Mutex<Option<(String, ChannelId)>>> = Arc::new(Mutex::new(None));
+}
+
+/// Count a mention of an account, returning false if its channel is already being viewed
+pub fn add(account: &str, channel: ChannelId) -> bool {
+    let key = (account.to_owned(), channel);
+    if CURRENT.lock().as_ref() == Some(&key) {
+        return false;
+    }
+    *UNREAD.lock().entry(key).or_insert(0) += 1;
+    true
+}
+
+/// Remember the channel being viewed, marking its mentions as read
+pub fn set_current(account: &str, channel: Option<ChannelId>) {
+    let current = channel.map(|channel| (account.to_owned(), channel));
+    if let Some(key) = &current {
+        UNREAD.lock().remove(key);
+    }
+    *CURRENT.lock() = current;
+}
+
+/// The number of unread mentions of an account
+pub fn count(account: &str) -> usize {
+    UNREAD
+        .lock()
+        .iter()
+        .filter(|((mentioned, _), _)| mentioned == account)
+        .map(|(_, count)| count)
+        .sum()
+}
@@ -1,27 +1,113 @@
-use self::client::DiscordClient;
+use self::{
+    client::DiscordClient,
+    connection::{set_state, ConnectionState},
+};
 use crate::Discord;
 use lazy_static::lazy_static;
 use serenity::{client::Context, prelude::Mutex};
-use std::{sync::Arc, thread};
+use std::{collections::HashMap, sync::Arc, thread, time::Duration};
 
+pub mod attachments;
 mod client;
+pub mod connection;
 mod event_handler;
 pub mod formatting;
+pub mod forums;
+pub mod highlight;
+pub mod http;
+pub mod mentions;
+pub mod search;
+pub mod send_queue;
+pub mod threads;
 
+pub use client::ShardInfo;
 pub use event_handler::TYPING_EVENTS;
 
-pub static mut CONTEXT: Option<Context> = None;
+/// The name of the account using `weecord.main.token`
+pub const DEFAULT_ACCOUNT: &str = "";
 
-pub fn get_ctx() -> Option<&'static Context> {
-    unsafe { CONTEXT.as_ref() }
+lazy_static! {
+    static ref CONTEXTS: Arc<Mutex<HashMap<String, Arc<Context>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    static ref CLIENTS: Arc<Mutex<HashMap<String, DiscordClient>>> =
+        Arc::new(Mutex::new(HashMap::new()));
 }
 
-lazy_static! {
-    pub(crate) static ref DISCORD: Arc<Mutex<Option<DiscordClient>>> = Arc::new(Mutex::new(None));
+/// The context of a connected account
+pub fn get_ctx(account: &str) -> Option<Arc<Context>> {
+    CONTEXTS.lock().get(account).cloned()
+}
+
+pub(crate) fn set_ctx(account: &str, ctx: Context) {
+    CONTEXTS.lock().insert(account.to_owned(), Arc::new(ctx));
 }
 
-pub fn init(weecord: &Discord, token: &str, irc_mode: bool) {
-    let (discord_client, events) = match DiscordClient::start(weecord, token) {
+/// The names of all accounts that have connected, sorted with the default account first
+pub fn connected_accounts() -> Vec<String> {
+    let mut accounts: Vec<_> = CONTEXTS.lock().keys().cloned().collect();
+    accounts.sort();
+    accounts
+}
+
+/// The account used for buffers that do not belong to an account
+///
+/// This is the default account, unless only named accounts are connected
+pub fn default_account() -> String {
+    let contexts = CONTEXTS.lock();
+    if contexts.contains_key(DEFAULT_ACCOUNT) {
+        return DEFAULT_ACCOUNT.to_owned();
+    }
+    contexts
+        .keys()
+        .min()
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_ACCOUNT.to_owned())
+}
+
+/// A printable name for an account
+pub fn account_display_name(account: &str) -> &str {
+    if account == DEFAULT_ACCOUNT {
+        "default"
+    } else {
+        account
+    }
+}
+
+/// The account an account name given to a command refers to, the reverse of
+/// `account_display_name`
+pub fn parse_account_name(name: &str) -> &str {
+    if name == "default" {
+        DEFAULT_ACCOUNT
+    } else {
+        name
+    }
+}
+
+/// Whether an account is connected or trying to connect
+pub fn is_connected(account: &str) -> bool {
+    connection::state(account) != ConnectionState::Disconnected
+}
+
+/// The shards of a connected account
+pub fn shards(account: &str) -> Vec<ShardInfo> {
+    CLIENTS
+        .lock()
+        .get(account)
+        .map(DiscordClient::shards)
+        .unwrap_or_default()
+}
+
+/// The gateway latency of an account, once a heartbeat has been acknowledged
+pub fn latency(account: &str) -> Option<Duration> {
+    shards(account)
+        .into_iter()
+        .filter_map(|shard| shard.latency)
+        .max()
+}
+
+pub fn init(weecord: &Discord, account: &str, token: &str, irc_mode: bool) {
+    set_state(account, ConnectionState::Connecting);
+    let (discord_client, events) = match DiscordClient::start(weecord, account, token) {
         Ok(d) => d,
         Err(e) => {
             // Cannot use plugin_print because we haven't finished init
@@ -29,20 +115,51 @@ pub fn init(weecord: &Discord, token: &str, irc_mode: bool) {
                 "discord: An error occurred connecting to discord: {}",
                 e
             ));
+            set_state(account, ConnectionState::Disconnected);
             return;
         },
     };
 
+    let account = account.to_owned();
+    CLIENTS.lock().insert(account.clone(), discord_client);
+
     thread::spawn(move || {
         if let Ok(ready) = events.recv() {
-            crate::plugin_print("Discord connected");
+            if account == DEFAULT_ACCOUNT {
+                crate::plugin_print("Discord connected");
+            } else {
+                crate::plugin_print(&format!("Discord connected as {}", account));
+            }
             if irc_mode {
-                crate::buffers::create_autojoin_buffers(&ready);
+                crate::buffers::create_autojoin_buffers(&account, &ready);
             } else {
-                crate::buffers::create_buffers(&ready);
+                crate::buffers::create_buffers(&account, &ready);
             }
         }
     });
+}
 
-    *DISCORD.lock() = Some(discord_client);
+/// Shut down the clients of every account, returning false if none were connected
+pub fn disconnect_all() -> bool {
+    CONTEXTS.lock().clear();
+    let clients: Vec<_> = CLIENTS.lock().drain().collect();
+    for (account, client) in &clients {
+        set_state(account, ConnectionState::Disconnected);
+        client.shutdown();
+    }
+    !clients.is_empty()
+}
+
+/// Shut down the client of an account, returning false if it was not connected
+pub fn disconnect(account: &str) -> bool {
+    CONTEXTS.lock().remove(account);
+    set_state(account, ConnectionState::Disconnected);
+    let client = CLIENTS.lock().remove(account);
+    match client {
+        Some(client) => {
+            client.shutdown();
+            true
+        },
+        None => false,
+    }
 }
@@ -0,0 +1,77 @@
+use super::http;
+use serenity::model::prelude::*;
+
+/// Where a search is performed, guild searches cover every channel of the guild
+#[derive(Debug, Clone, Copy)]
+pub enum SearchScope {
+    Guild(GuildId),
+    Channel(ChannelId),
+}
+
+#[derive(Debug, Default)]
+pub struct SearchQuery {
+    pub content: String,
+    pub author: Option<UserId>,
+    pub channel: Option<ChannelId>,
+    /// Either `link` or `file`
+    pub has: Option<String>,
+}
+
+pub struct SearchResults {
+    pub total: u64,
+    pub messages: Vec<Message>,
+}
+
+/// The error code for searches made before Discord has finished indexing the guild or channel
+const INDEX_NOT_READY: u64 = 110_000;
+
+/// Search messages using Discord's search endpoint
+///
+/// This blocks until the request completes, so it must not be called on the main thread
+pub fn search(
+    account: &str,
+    scope: SearchScope,
+    query: &SearchQuery,
+) -> Result<SearchResults, http::Error> {
+    let path = match scope {
+        SearchScope::Guild(guild) => format!("/guilds/{}/messages/search", guild.0),
+        SearchScope::Channel(channel) => format!("/channels/{}/messages/search", channel.0),
+    };
+
+    let mut params = Vec::new();
+    if !query.content.is_empty() {
+        params.push(("content", query.content.clone()));
+    }
+    if let Some(author) = query.author {
+        params.push(("author_id", author.0.to_string()));
+    }
+    if let Some(channel) = query.channel {
+        params.push(("channel_id", channel.0.to_string()));
+    }
+    if let Some(has) = &query.has {
+        params.push(("has", has.clone()));
+    }
+
+    let body = http::get(account, &path, &params)?;
+    if body["code"] == INDEX_NOT_READY {
+        return Err(http::Error::Api(
+            "search index is not ready yet, try again soon".to_owned(),
+        ));
+    }
+    let total = body["total_results"].as_u64().unwrap_or_default();
+
+    // Each result is a list of messages surrounding the hit, only the hit itself is kept
+    let mut messages = Vec::new();
+    if let Some(results) = body["messages"].as_array() {
+        for context in results {
+            let hit = context
+                .as_array()
+                .and_then(|context| context.iter().find(|msg| msg["hit"] == true));
+            if let Some(hit) = hit {
+                messages.push(serde_json::from_value(hit.clone())?);
+            }
+        }
+    }
+
+    Ok(SearchResults { total, messages })
+}
@@ -0,0 +1,298 @@
+//! Outgoing messages, edits, deletes and reactions
+//!
+//! Every channel has its own queue, which is worked off in order by a background thread so the
+//! main thread never waits on the network. Requests go through serenity's http client, which
+//! waits for the rate limit bucket of each route. While an account is resuming or reconnecting,
+//! the queue waits for it to be ready again. Failed actions can be queued again with
+//! `/discord retry`, which runs them before the rest of the queue.
+
+use super::connection::{self, ConnectionState};
+use crate::{on_main, plugin_print, utils, utils::BufferExt};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde_json::json;
+use serenity::model::prelude::*;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use weechat::Buffer;
+
+#[derive(Debug, Clone)]
+pub enum Action {
+    Send(String),
+    Reply {
+        target: MessageId,
+        content: String,
+    },
+    /// Replace `old` with `new` in the nth most recent own message
+    Edit {
+        line: usize,
+        old: String,
+        new: String,
+        global: bool,
+    },
+    /// Delete the nth most recent own message
+    Delete {
+        line: usize,
+    },
+    React {
+        message: MessageId,
+        reaction: ReactionType,
+        add: bool,
+    },
+}
+
+impl Action {
+    fn description(&self) -> &'static str {
+        match self {
+            Action::Send(_) => "send message",
+            Action::Reply { .. } => "send reply",
+            Action::Edit { .. } => "edit message",
+            Action::Delete { .. } => "delete message",
+            Action::React { add: true, .. } => "add reaction",
+            Action::React { add: false, .. } => "remove reaction",
+        }
+    }
+}
+
+struct Job {
+    id: u64,
+    account: String,
+    buffer_name: String,
+    channel: ChannelId,
+    guild: Option<GuildId>,
+    action: Action,
+}
+
+impl Job {
+    /// Sent along with messages, so their local echo can be matched with the message that
+    /// Discord sends back
+    fn nonce(&self) -> String {
+        self.id.to_string()
+    }
+}
+
+/// How many failed actions are kept for `/discord retry`
+const MAX_FAILED: usize = 50;
+
+lazy_static! {
+    static ref QUEUES: Arc<Mutex<HashMap<(String, ChannelId), VecDeque<Job>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    static ref FAILED: Arc<Mutex<BTreeMap<u64, Job>>> = Arc::new(Mutex::new(BTreeMap::new()));
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Send a message to the channel of `buffer`, showing `echo` until it has been sent
+pub fn send(buffer: &Buffer, content: String, echo: &str) {
+    push(buffer, Action::Send(content), Some(echo));
+}
+
+/// Send a reply to `target` in the channel of `buffer`, showing `echo` until it has been sent
+pub fn reply(buffer: &Buffer, target: MessageId, content: String, echo: &str) {
+    push(buffer, Action::Reply { target, content }, Some(echo));
+}
+
+/// Queue an action in the channel of `buffer`
+pub fn queue(buffer: &Buffer, action: Action) {
+    push(buffer, action, None);
+}
+
+fn push(buffer: &Buffer, action: Action, echo: Option<&str>) {
+    let channel = match buffer.channel_id() {
+        Some(channel) => channel,
+        None => return,
+    };
+    let job = Job {
+        id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+        account: buffer.account(),
+        buffer_name: buffer.get_name().to_string(),
+        channel,
+        guild: buffer.guild_id(),
+        action,
+    };
+
+    if let (Some(echo), Some(ctx)) = (echo, super::get_ctx(&job.account)) {
+        let weechat = buffer.get_weechat();
+        let weecord = crate::upgrade_plugin(&weechat);
+        if let Some(manager) = weecord.buffer_manager.get_buffer(&job.buffer_name) {
+            let prefix = format!(
+                "{}{}{}",
+                weechat.color("chat_nick_self"),
+                ctx.cache.read().user.name,
+                weechat.color("reset")
+            );
+            manager.add_pending(job.id, &job.nonce(), &prefix, echo);
+        }
+    }
+
+    enqueue(job, false);
+}
+
+/// Add a job to the queue of its channel, at the front if `first` is set
+fn enqueue(job: Job, first: bool) {
+    let key = (job.account.clone(), job.channel);
+    let mut queues = QUEUES.lock();
+    match queues.get_mut(&key) {
+        // The worker of the channel is still running
+        Some(queue) if first => queue.push_front(job),
+        Some(queue) => queue.push_back(job),
+        None => {
+            queues.insert(key.clone(), VecDeque::from(vec![job]));
+            thread::spawn(move || work(key));
+        },
+    }
+}
+
+fn work(key: (String, ChannelId)) {
+    loop {
+        let job = {
+            let mut queues = QUEUES.lock();
+            match queues.get_mut(&key).and_then(VecDeque::pop_front) {
+                Some(job) => job,
+                None => {
+                    queues.remove(&key);
+                    return;
+                },
+            }
+        };
+        let result = run(&job);
+        on_main(move |weecord| finish(weecord, job, result));
+    }
+}
+
+fn run(job: &Job) -> Result<Option<Message>, String> {
+    // Wait for a lost connection to come back instead of failing right away
+    loop {
+        match connection::state(&job.account) {
+            ConnectionState::Ready => break,
+            ConnectionState::Disconnected => return Err("not connected".to_owned()),
+            _ => thread::sleep(Duration::from_secs(1)),
+        }
+    }
+    let ctx = super::get_ctx(&job.account).ok_or_else(|| "not connected".to_owned())?;
+    let channel = job.channel;
+
+    let result = match &job.action {
+        Action::Send(content) => ctx
+            .http
+            .send_message(
+                channel.0,
+                &json!({
+                    "content": content,
+                    "nonce": job.nonce(),
+                }),
+            )
+            .map(Some),
+        Action::Reply { target, content } => {
+            utils::send_reply(&*ctx, channel, job.guild, *target, content, &job.nonce()).map(Some)
+        },
+        Action::Edit {
+            line,
+            old,
+            new,
+            global,
+        } => utils::get_users_nth_message(&*ctx, channel, *line).and_then(|mut msg| {
+            let content = if *global {
+                msg.content.replace(old.as_str(), new)
+            } else {
+                msg.content.replacen(old.as_str(), new, 1)
+            };
+            msg.edit(&*ctx, |e| e.content(content)).map(|_| None)
+        }),
+        Action::Delete { line } => utils::get_users_nth_message(&*ctx, channel, *line)
+            .and_then(|msg| channel.delete_message(&ctx.http, msg.id))
+            .map(|_| None),
+        Action::React {
+            message,
+            reaction,
+            add: true,
+        } => channel
+            .create_reaction(&ctx.http, *message, reaction.clone())
+            .map(|_| None),
+        Action::React {
+            message,
+            reaction,
+            add: false,
+        } => channel
+            .delete_reaction(&ctx.http, *message, None, reaction.clone())
+            .map(|_| None),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+fn finish(weecord: &crate::Discord, job: Job, result: Result<Option<Message>, String>) {
+    let manager = weecord.buffer_manager.get_buffer(&job.buffer_name);
+    match result {
+        Ok(sent) => {
+            if let (Some(manager), Some(msg), Some(ctx)) =
+                (manager, sent, super::get_ctx(&job.account))
+            {
+                manager.confirm_pending(&ctx.cache, job.id, &msg);
+            }
+        },
+        Err(e) => {
+            let line = format!(
+                "Unable to {}: {}, retry with /discord retry {}",
+                job.action.description(),
+                e,
+                job.id
+            );
+            match manager {
+                Some(manager) => {
+                    manager.set_pending_failed(job.id, true);
+                    manager.print(&format!("{}\t{}", weecord.get_prefix("network"), line));
+                },
+                None => plugin_print(&line),
+            }
+            let mut failed = FAILED.lock();
+            failed.insert(job.id, job);
+            // The oldest failures are forgotten, along with their local echo
+            while failed.len() > MAX_FAILED {
+                let oldest = *failed.keys().next().unwrap();
+                if let Some(job) = failed.remove(&oldest) {
+                    if let Some(manager) = weecord.buffer_manager.get_buffer(&job.buffer_name) {
+                        manager.discard_pending(job.id);
+                    }
+                }
+            }
+        },
+    }
+}
+
+/// Queue a failed action again, or the most recent failed action in the channel of `buffer`
+///
+/// It runs before the actions that are still queued in its channel.
+pub fn retry(buffer: &Buffer, id: Option<u64>) {
+    let job = {
+        let mut failed = FAILED.lock();
+        let id = id.or_else(|| {
+            let (account, channel) = (buffer.account(), buffer.channel_id()?);
+            failed
+                .values()
+                .rev()
+                .find(|job| job.account == account && job.channel == channel)
+                .map(|job| job.id)
+        });
+        id.and_then(|id| failed.remove(&id))
+    };
+    let job = match job {
+        Some(job) => job,
+        None => {
+            plugin_print("No failed message to retry");
+            return;
+        },
+    };
+
+    let weecord = crate::upgrade_plugin(&buffer.get_weechat());
+    if let Some(manager) = weecord.buffer_manager.get_buffer(&job.buffer_name) {
+        manager.set_pending_failed(job.id, false);
+    }
+    enqueue(job, true);
+}
@@ -0,0 +1,187 @@
+//! Threads are not supported by serenity, so they are tracked here from the raw gateway events and
+//! managed through the http api
+
+use super::http::{self, Method};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use serenity::model::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+const GUILD_NEWS_THREAD: u64 = 10;
+const GUILD_PUBLIC_THREAD: u64 = 11;
+const GUILD_PRIVATE_THREAD: u64 = 12;
+
+/// How many minutes of inactivity before a new thread is archived
+const AUTO_ARCHIVE_DURATION: u64 = 1440;
+
+#[derive(Debug, Clone)]
+pub struct Thread {
+    pub id: ChannelId,
+    pub guild_id: GuildId,
+    pub parent_id: ChannelId,
+    pub name: String,
+    pub private: bool,
+    pub archived: bool,
+    /// The number of messages in the thread, not counting the starting message
+    pub message_count: u64,
+    pub last_message_id: Option<MessageId>,
+    /// The forum tags applied to a forum post
+    pub applied_tags: Vec<u64>,
+}
+
+impl Thread {
+    /// Parse a thread from a raw channel object, returns `None` if the channel is not a thread
+    pub fn from_value(value: &Value) -> Option<Thread> {
+        let kind = value["type"].as_u64()?;
+        if kind != GUILD_NEWS_THREAD && kind != GUILD_PUBLIC_THREAD && kind != GUILD_PRIVATE_THREAD
+        {
+            return None;
+        }
+
+        Some(Thread {
+            id: ChannelId(parse_id(&value["id"])?),
+            guild_id: GuildId(parse_id(&value["guild_id"])?),
+            parent_id: ChannelId(parse_id(&value["parent_id"])?),
+            name: value["name"].as_str()?.to_owned(),
+            private: kind == GUILD_PRIVATE_THREAD,
+            archived: value["thread_metadata"]["archived"]
+                .as_bool()
+                .unwrap_or_default(),
+            message_count: value["message_count"].as_u64().unwrap_or_default(),
+            last_message_id: parse_id(&value["last_message_id"]).map(MessageId),
+            applied_tags: value["applied_tags"]
+                .as_array()
+                .map(|tags| tags.iter().filter_map(parse_id).collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+pub(crate) fn parse_id(value: &Value) -> Option<u64> {
+    value.as_str()?.parse().ok()
+}
+
+lazy_static! {
+    // Threads are keyed by account, as each account can only see the threads it has access to
+    static ref THREADS: Arc<Mutex<HashMap<(String, ChannelId), Thread>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    /// Channels that have been fetched or are being fetched, including those that are not threads
+    static ref FETCHED: Arc<Mutex<HashSet<(String, ChannelId)>>> =
+        Arc::new(Mutex::new(HashSet::new()));
+}
+
+/// Get a known thread of an account
+pub fn get(account: &str, id: ChannelId) -> Option<Thread> {
+    THREADS.lock().get(&(account.to_owned(), id)).cloned()
+}
+
+/// Get all known threads of a channel
+pub fn threads_in(account: &str, parent: ChannelId) -> Vec<Thread> {
+    THREADS
+        .lock()
+        .iter()
+        .filter(|((thread_account, _), thread)| {
+            thread_account == account && thread.parent_id == parent
+        })
+        .map(|(_, thread)| thread.clone())
+        .collect()
+}
+
+pub fn insert(account: &str, thread: Thread) {
+    THREADS
+        .lock()
+        .insert((account.to_owned(), thread.id), thread);
+}
+
+pub fn remove(account: &str, id: ChannelId) -> Option<Thread> {
+    THREADS.lock().remove(&(account.to_owned(), id))
+}
+
+/// Fetch a channel, and remember it if it is a thread
+///
+/// Every channel is only fetched once, unless the request fails.
+pub fn fetch(account: &str, id: ChannelId) -> Option<Thread> {
+    let key = (account.to_owned(), id);
+    if !FETCHED.lock().insert(key.clone()) {
+        return get(account, id);
+    }
+
+    let value = match http::get(account, &format!("/channels/{}", id.0), &[]) {
+        Ok(value) => value,
+        Err(_) => {
+            FETCHED.lock().remove(&key);
+            return None;
+        },
+    };
+    let thread = Thread::from_value(&value)?;
+    insert(account, thread.clone());
+    Some(thread)
+}
+
+/// Create a new thread in `parent`
+pub fn create(
+    account: &str,
+    parent: ChannelId,
+    name: &str,
+    private: bool,
+) -> Result<Thread, http::Error> {
+    let kind = if private {
+        GUILD_PRIVATE_THREAD
+    } else {
+        GUILD_PUBLIC_THREAD
+    };
+    let body = json!({
+        "name": name,
+        "type": kind,
+        "auto_archive_duration": AUTO_ARCHIVE_DURATION,
+    });
+    let value = http::request(
+        account,
+        Method::Post,
+        &format!("/channels/{}/threads", parent.0),
+        &[],
+        Some(&body),
+    )?;
+
+    let thread = Thread::from_value(&value)
+        .ok_or_else(|| http::Error::Api("response was not a thread".to_owned()))?;
+    insert(account, thread.clone());
+    Ok(thread)
+}
+
+pub fn join(account: &str, id: ChannelId) -> Result<(), http::Error> {
+    http::request(
+        account,
+        Method::Put,
+        &format!("/channels/{}/thread-members/@me", id.0),
+        &[],
+        None,
+    )
+    .map(|_| ())
+}
+
+pub fn leave(account: &str, id: ChannelId) -> Result<(), http::Error> {
+    http::request(
+        account,
+        Method::Delete,
+        &format!("/channels/{}/thread-members/@me", id.0),
+        &[],
+        None,
+    )
+    .map(|_| ())
+}
+
+pub fn archive(account: &str, id: ChannelId) -> Result<(), http::Error> {
+    http::request(
+        account,
+        Method::Patch,
+        &format!("/channels/{}", id.0),
+        &[],
+        Some(&json!({ "archived": true })),
+    )
+    .map(|_| ())
+}
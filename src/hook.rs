@@ -1,7 +1,8 @@
 use crate::{
     buffers::load_pin_buffer_history,
     command::Args,
-    discord, on_main, plugin_print, utils,
+    discord::{self, send_queue},
+    on_main, plugin_print, utils,
     utils::{BufferExt, ChannelExt},
 };
 use crossbeam_channel::unbounded;
@@ -26,11 +27,13 @@ pub struct HookHandles {
     _query_handle: weechat::CommandRunHook<()>,
     _nick_handle: weechat::CommandRunHook<()>,
     _join_handle: weechat::CommandRunHook<()>,
+    _scroll_top_handle: weechat::CommandRunHook<()>,
     _guild_completion_handle: weechat::CompletionHook<()>,
     _channel_completion_handle: weechat::CompletionHook<()>,
     _dm_completion_handle: weechat::CompletionHook<()>,
     _nick_completion_handle: weechat::CompletionHook<()>,
     _role_completion_handle: weechat::CompletionHook<()>,
+    _account_completion_handle: weechat::CompletionHook<()>,
 }
 
 pub fn init(weechat: &Weechat) -> HookHandles {
@@ -55,9 +58,10 @@ pub fn init(weechat: &Weechat) -> HookHandles {
                 return ReturnCode::Error;
             };
 
-            handle_query(&Args::from_cmd(
-                &command.replace("/query ", "/discord query "),
-            ))
+            handle_query(
+                buffer,
+                &Args::from_cmd(&command.replace("/query ", "/discord query ")),
+            )
         },
         None,
     );
@@ -74,6 +78,12 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         None,
     );
 
+    let _scroll_top_handle = weechat.hook_command_run(
+        "/window scroll_top",
+        |_, ref buffer, _| handle_scroll_top(buffer),
+        None,
+    );
+
     let _guild_completion_handle = weechat.hook_completion(
         "weecord_guild_completion",
         "Completion for discord guilds",
@@ -109,6 +119,13 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         None,
     );
 
+    let _account_completion_handle = weechat.hook_completion(
+        "weecord_account_completion",
+        "Completion for weecord accounts",
+        |_, ref buffer, _, completions| handle_account_completion(buffer, completions),
+        None,
+    );
+
     HookHandles {
         _buffer_switch_handle,
         _buffer_typing_handle,
@@ -116,11 +133,13 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         _query_handle,
         _nick_handle,
         _join_handle,
+        _scroll_top_handle,
         _guild_completion_handle,
         _channel_completion_handle,
         _dm_completion_handle,
         _nick_completion_handle,
         _role_completion_handle,
+        _account_completion_handle,
     }
 }
 
@@ -130,70 +149,140 @@ pub fn buffer_input(buffer: Buffer, text: &str) {
     } else {
         text
     };
-    let channel = buffer.channel_id();
+    if utils::is_search_buffer(&buffer) {
+        let weechat = buffer.get_weechat();
+        let weecord = crate::upgrade_plugin(&weechat);
+        if let (Ok(n), Some(manager)) = (
+            text.trim().parse(),
+            weecord.buffer_manager.get_buffer(&buffer.get_name()),
+        ) {
+            crate::buffers::open_search_result(weecord, &manager, n);
+        }
+        return;
+    }
+    if utils::is_forum_buffer(&buffer) {
+        let weechat = buffer.get_weechat();
+        let weecord = crate::upgrade_plugin(&weechat);
+        if let (Ok(n), Some(manager)) = (
+            text.trim().parse(),
+            weecord.buffer_manager.get_buffer(&buffer.get_name()),
+        ) {
+            crate::buffers::open_forum_post(weecord, &manager, n);
+        }
+        return;
+    }
+
     let guild = buffer.guild_id();
 
-    if let Some(channel) = channel {
-        let ctx = match crate::discord::get_ctx() {
+    if buffer.channel_id().is_some() {
+        // Actions are queued while the connection is coming back
+        let ctx = match discord::connection::queue_ctx(&buffer.account()) {
             Some(ctx) => ctx,
             _ => return,
         };
 
         if let Some(edit) = parsing::parse_line_edit(text) {
-            let weechat = buffer.get_weechat();
-            match edit {
-                parsing::LineEdit::Delete { line } => {
-                    if let Err(e) = crate::utils::get_users_nth_message(&ctx, channel, line)
-                        .map(|msg| channel.delete_message(&ctx.http, msg.id))
-                    {
-                        buffer.print(&format!(
-                            "{}\tAn error occurred deleting a message: {}",
-                            weechat.get_prefix("network"),
-                            e
-                        ));
-                    }
-                },
+            let action = match edit {
+                parsing::LineEdit::Delete { line } => send_queue::Action::Delete { line },
                 parsing::LineEdit::Sub {
                     line,
                     old,
                     new,
                     options,
-                } => {
-                    // TODO: Clean this up, (try block)?
-                    if let Err(e) =
-                        crate::utils::get_users_nth_message(&ctx, channel, line).map(|mut msg| {
-                            let orig = msg.content.clone();
-                            msg.edit(ctx, |e| {
-                                if options.map(|o| o.contains('g')).unwrap_or_default() {
-                                    e.content(orig.replace(old, new))
-                                } else {
-                                    e.content(orig.replacen(old, new, 1))
-                                }
-                            })
-                        })
-                    {
-                        buffer.print(&format!(
-                            "{}\tAn error occurred editing a message: {}",
-                            weechat.get_prefix("network"),
-                            e
-                        ));
-                    }
+                } => send_queue::Action::Edit {
+                    line,
+                    old: old.to_owned(),
+                    // The replacement is sent like a message, the old text is matched as it is
+                    new: utils::format_outgoing(&buffer.get_weechat(), new),
+                    global: options.map(|o| o.contains('g')).unwrap_or_default(),
                 },
+            };
+            send_queue::queue(&buffer, action);
+            return;
+        }
+        if let Some(reply) = parsing::parse_reply(text) {
+            let weechat = buffer.get_weechat();
+            let weecord = crate::upgrade_plugin(&weechat);
+            let target = weecord
+                .buffer_manager
+                .get_buffer(&buffer.get_name())
+                .and_then(|manager| manager.nth_message(reply.line));
+            match target {
+                Some(target) => send_reply(&buffer, &target, reply.text),
+                None => buffer.print(&format!(
+                    "{}\tUnable to find message {} to reply to",
+                    weechat.get_prefix("network"),
+                    reply.line
+                )),
             }
             return;
         }
-        let text = utils::create_mentions(&ctx.cache, guild, text);
-        let text = utils::expand_guild_emojis(&ctx.cache, guild, &text);
-        if let Err(e) = channel.say(ctx, text) {
+        if let Some(reaction) = parsing::parse_reaction(text) {
             let weechat = buffer.get_weechat();
-            buffer.print(&format!(
-                "{}\tUnable to send message to {}: {:#?}",
-                weechat.get_prefix("network"),
-                channel.0,
-                e
-            ));
+            let (line, emoji, add) = match reaction {
+                parsing::ReactionEdit::Add { line, emoji } => (line, emoji, true),
+                parsing::ReactionEdit::Remove { line, emoji } => (line, emoji, false),
+            };
+            let weecord = crate::upgrade_plugin(&weechat);
+            let target = weecord
+                .buffer_manager
+                .get_buffer(&buffer.get_name())
+                .and_then(|manager| manager.nth_message(line));
+            let target = match target {
+                Some(target) => target,
+                None => {
+                    buffer.print(&format!(
+                        "{}\tUnable to find message {} to react to",
+                        weechat.get_prefix("network"),
+                        line
+                    ));
+                    return;
+                },
+            };
+            let reaction = match utils::resolve_reaction(&ctx.cache, guild, emoji) {
+                Some(reaction) => reaction,
+                None => {
+                    buffer.print(&format!(
+                        "{}\tUnknown emoji {}",
+                        weechat.get_prefix("network"),
+                        emoji
+                    ));
+                    return;
+                },
+            };
+
+            send_queue::queue(
+                &buffer,
+                send_queue::Action::React {
+                    message: target.id,
+                    reaction,
+                    add,
+                },
+            );
+            return;
         }
+        let content = utils::create_mentions(&ctx.cache, guild, text);
+        let content = utils::expand_guild_emojis(&ctx.cache, guild, &content);
+        let content = utils::format_outgoing(&buffer.get_weechat(), &content);
+        send_queue::send(&buffer, content, text);
+    }
+}
+
+/// Send `text` to the channel of `buffer` as a reply to `target`
+pub fn send_reply(buffer: &Buffer, target: &Message, text: &str) {
+    if buffer.channel_id().is_none() {
+        return;
     }
+    let guild = buffer.guild_id();
+    let ctx = match discord::connection::queue_ctx(&buffer.account()) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+
+    let content = utils::create_mentions(&ctx.cache, guild, text);
+    let content = utils::expand_guild_emojis(&ctx.cache, guild, &content);
+    let content = utils::format_outgoing(&buffer.get_weechat(), &content);
+    send_queue::reply(buffer, target.id, content, text);
 }
 
 fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
@@ -201,6 +290,11 @@ fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
         let buffer = unsafe { crate::utils::buffer_from_ptr(buffer_ptr) };
         let weechat = buffer.get_weechat();
         let weecord = crate::upgrade_plugin(&weechat);
+
+        // Mentions are read once their buffer is viewed
+        discord::mentions::set_current(&buffer.account(), buffer.channel_id());
+        weecord.update_bar_item("discord_status");
+
         let buffer = match weecord
             .buffer_manager
             .get_buffer(buffer.get_name().as_ref())
@@ -218,6 +312,10 @@ fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
                 load_pin_buffer_history(&buffer);
                 return ReturnCode::Ok;
             }
+            if utils::is_forum_buffer(&buffer) {
+                crate::buffers::load_forum_index(&buffer);
+                return ReturnCode::Ok;
+            }
 
             let fetch_count = weecord.config.message_fetch_count.value();
 
@@ -229,34 +327,40 @@ fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
         }
 
         let channel_id = buffer.channel_id();
+        let account = buffer.account();
 
         thread::spawn(move || {
             if rx.recv().is_err() {
                 return;
             }
-            let ctx = match discord::get_ctx() {
+            let ctx = match discord::get_ctx(&account) {
                 Some(s) => s,
                 None => return,
             };
-            if let Some(channel) = channel_id.and_then(|id| id.to_channel_cached(&ctx)) {
+            if let Some(channel) = channel_id.and_then(|id| id.to_channel_cached(&*ctx)) {
                 if let Some(guild_channel) = channel.clone().guild() {
                     let guild_id = guild_channel.read().guild_id;
                     use std::collections::{HashMap, HashSet};
                     lazy_static! {
-                        static ref CHANNELS: Arc<Mutex<HashMap<GuildId, HashSet<ChannelId>>>> =
-                            Arc::new(Mutex::new(HashMap::new()));
+                        // The channels subscribed to by each account
+                        static ref CHANNELS: Arc<
+                            Mutex<HashMap<(String, GuildId), HashSet<ChannelId>>>,
+                        > = Arc::new(Mutex::new(HashMap::new()));
                     }
 
+                    let key = (account, guild_id);
                     let mut channels = CHANNELS.lock();
-                    let send = if let Some(guild_channels) = channels.get_mut(&guild_id) {
+                    let send = if let Some(guild_channels) = channels.get_mut(&key) {
                         guild_channels.insert(channel.id())
                     } else {
-                        channels
-                            .insert(guild_id, HashSet::from_iter(vec![channel.id()].into_iter()));
+                        channels.insert(
+                            key.clone(),
+                            HashSet::from_iter(vec![channel.id()].into_iter()),
+                        );
                         true
                     };
                     if send {
-                        let channels = channels.get(&guild_id).unwrap();
+                        let channels = channels.get(&key).unwrap();
                         let channels_obj: HashMap<String, Vec<Vec<_>>> = HashMap::from_iter(
                             channels
                                 .iter()
@@ -280,7 +384,7 @@ fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
                 if let Some(rs) = ctx.cache.read().read_state.get(&channel.id()) {
                     if let Some(last_message_id) = channel.last_message() {
                         if rs.last_message_id != last_message_id {
-                            let _ = channel.id().ack_message(&ctx, last_message_id);
+                            let _ = channel.id().ack_message(&*ctx, last_message_id);
                         }
                     }
                 }
@@ -312,8 +416,9 @@ fn handle_buffer_typing(weechat: &Weechat, data: weechat::SignalHookValue) -> Re
                 if *LAST_TYPING_TIMESTAMP.lock() + 9 < timestamp_now {
                     *LAST_TYPING_TIMESTAMP.lock() = timestamp_now;
 
+                    let account = buffer.account();
                     std::thread::spawn(move || {
-                        let ctx = match discord::get_ctx() {
+                        let ctx = match discord::get_ctx(&account) {
                             Some(s) => s,
                             None => return,
                         };
@@ -343,7 +448,7 @@ fn handle_channel_completion(buffer: &Buffer, completion: weechat::Completion) -
     };
 
     // Match mangled name to the real name
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
@@ -371,8 +476,8 @@ fn handle_channel_completion(buffer: &Buffer, completion: weechat::Completion) -
     ReturnCode::Ok
 }
 
-fn handle_guild_completion(_buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+fn handle_guild_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
@@ -383,8 +488,8 @@ fn handle_guild_completion(_buffer: &Buffer, completion: weechat::Completion) ->
     ReturnCode::Ok
 }
 
-fn handle_dm_completion(_buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+fn handle_dm_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
@@ -395,14 +500,14 @@ fn handle_dm_completion(_buffer: &Buffer, completion: weechat::Completion) -> Re
 }
 
 fn handle_nick_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
 
     let channel_id = buffer.channel_id();
 
-    if let Some(Channel::Guild(channel)) = channel_id.and_then(|c| c.to_channel(ctx).ok()) {
+    if let Some(Channel::Guild(channel)) = channel_id.and_then(|c| c.to_channel(&*ctx).ok()) {
         let channel = channel.read();
 
         if let Ok(members) = channel.members(&ctx.cache) {
@@ -420,7 +525,7 @@ fn handle_nick_completion(buffer: &Buffer, completion: weechat::Completion) -> R
 }
 
 fn handle_role_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
@@ -439,9 +544,17 @@ fn handle_role_completion(buffer: &Buffer, completion: weechat::Completion) -> R
     ReturnCode::Ok
 }
 
+fn handle_account_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let weechat = buffer.get_weechat();
+    for account in crate::upgrade_plugin(&weechat).config.accounts() {
+        completion.add(&account);
+    }
+    ReturnCode::Ok
+}
+
 // TODO: Make this faster
 // TODO: Handle command options
-pub fn handle_query(args: &Args) -> ReturnCode {
+pub fn handle_query(buffer: &Buffer, args: &Args) -> ReturnCode {
     let mut owned_args = args.clone();
 
     let mut noswitch = false;
@@ -467,9 +580,10 @@ pub fn handle_query(args: &Args) -> ReturnCode {
         Some(target) => target.to_owned(),
         None => return ReturnCode::Ok,
     };
+    let account = buffer.account();
 
     thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match discord::connection::ready_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
@@ -488,9 +602,9 @@ pub fn handle_query(args: &Args) -> ReturnCode {
         }
 
         if found_members.is_empty() {
-            let guilds = current_user.guilds(ctx).expect("Unable to fetch guilds");
+            let guilds = current_user.guilds(&*ctx).expect("Unable to fetch guilds");
             for guild in &guilds {
-                if let Some(guild) = guild.id.to_guild_cached(ctx) {
+                if let Some(guild) = guild.id.to_guild_cached(&*ctx) {
                     let guild = guild.read().clone();
                     for m in guild.members_containing(&target.to_lowercase(), false, true) {
                         found_members.push(m.user.read().clone());
@@ -503,15 +617,16 @@ pub fn handle_query(args: &Args) -> ReturnCode {
         let current_user_name = current_user.name.clone();
 
         if let Some(target) = found_members.get(0) {
-            if let Ok(chan) = target.create_dm_channel(ctx) {
+            if let Ok(chan) = target.create_dm_channel(&*ctx) {
                 on_main(move |weecord| {
-                    let ctx = match crate::discord::get_ctx() {
+                    let ctx = match crate::discord::get_ctx(&account) {
                         Some(ctx) => ctx,
                         _ => return,
                     };
                     crate::buffers::create_buffer_from_dm(
                         &ctx.cache,
                         &weecord,
+                        &account,
                         Channel::Private(Arc::new(RwLock::new(chan))),
                         &current_user_name,
                         !noswitch,
@@ -534,10 +649,11 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
         return ReturnCode::Ok;
     };
 
+    let account = buffer.account();
     let guilds;
     let mut substr;
     {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match discord::connection::ready_ctx(&account) {
             Some(ctx) => ctx,
             _ => return ReturnCode::Error,
         };
@@ -552,7 +668,7 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
 
             // TODO: Error handling
             current_user
-                .guilds(ctx)
+                .guilds(&*ctx)
                 .unwrap_or_default()
                 .iter()
                 .map(|g| g.id)
@@ -564,7 +680,7 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
 
     thread::spawn(move || {
         {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
@@ -575,7 +691,7 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
                 } else {
                     Some(substr.as_str())
                 };
-                let _ = guild.edit_nickname(ctx, new_nick);
+                let _ = guild.edit_nickname(&*ctx, new_nick);
                 // Make it less spammy
                 if should_sleep {
                     thread::sleep(Duration::from_secs(1));
@@ -586,11 +702,27 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
     ReturnCode::OkEat
 }
 
+// Fetch older messages when jumping to the top of a buffer
+fn handle_scroll_top(buffer: &Buffer) -> ReturnCode {
+    if buffer.channel_id().is_none() || utils::is_forum_buffer(buffer) {
+        return ReturnCode::Ok;
+    }
+
+    let weechat = buffer.get_weechat();
+    let weecord = crate::upgrade_plugin(&weechat);
+    if let Some(manager) = weecord.buffer_manager.get_buffer(&buffer.get_name()) {
+        let fetch_count = weecord.config.message_fetch_count.value();
+        crate::buffers::load_older_messages(&manager, fetch_count as u64);
+    }
+    ReturnCode::Ok
+}
+
 fn handle_join(buffer: &Buffer, command: &str) -> ReturnCode {
     let verbose = buffer.guild_id().is_some();
 
     crate::command::join(
         &buffer.get_weechat(),
+        &buffer.account(),
         &crate::command::Args::from_cmd(&format!("/discord {}", &command[1..])),
         verbose,
     )
@@ -6,12 +6,13 @@ mod command;
 mod config;
 mod discord;
 mod hook;
+mod message_store;
 mod sync;
 mod utils;
 mod weechat_utils;
 
 use crate::weechat_utils::BufferManager;
-pub use sync::{on_main, on_main_blocking, upgrade_plugin};
+pub use sync::{on_main, on_main_blocking, on_main_later, upgrade_plugin};
 
 use std::borrow::Cow;
 use weechat::{weechat_plugin, ArgsWeechat, ConfigOption, Weechat, WeechatPlugin, WeechatResult};
@@ -34,7 +35,7 @@ impl WeechatPlugin for Discord {
         let _hook_handles = hook::init(&weechat);
         let _bar_handles = bar_items::init(&weechat);
         let config = config::init(&weechat);
-        let buffer_manager = buffers::init(&weechat);
+        let buffer_manager = buffers::init(&weechat, &config);
 
         let autostart = config.autostart.value();
 
@@ -48,7 +49,7 @@ impl WeechatPlugin for Discord {
         };
 
         if !args.contains(&"-a".to_owned()) && autostart {
-            weecord.connect();
+            weecord.connect(None);
         }
 
         Ok(weecord)
@@ -56,12 +57,51 @@ impl WeechatPlugin for Discord {
 }
 
 impl Discord {
-    fn connect(&self) {
-        if unsafe { &crate::discord::CONTEXT }.is_some() {
-            plugin_print("Already connected");
+    /// Connect an account, or the default account and every named account if none is given
+    fn connect(&self, account: Option<&str>) {
+        let accounts = match account {
+            Some(account) => vec![account.to_owned()],
+            None => {
+                let mut accounts = self.config.accounts();
+                // The default account is optional once named accounts are set up
+                if accounts.is_empty() || !self.config.token.value().is_empty() {
+                    accounts.insert(0, discord::DEFAULT_ACCOUNT.to_owned());
+                }
+                accounts
+            },
+        };
+
+        for account in accounts {
+            self.connect_account(&account);
+        }
+    }
+
+    fn connect_account(&self, account: &str) {
+        if discord::is_connected(account) {
+            plugin_print(&format!(
+                "Already {} ({})",
+                discord::connection::state(account),
+                discord::account_display_name(account)
+            ));
+            return;
         }
 
-        let token = self.config.token.value().into_owned();
+        let option_name = if account == discord::DEFAULT_ACCOUNT {
+            "weecord.main.token".to_owned()
+        } else {
+            format!("weecord.account.{}.token", account)
+        };
+        let token = match self.get_string_option(&option_name) {
+            Some(token) => token.value().into_owned(),
+            None => {
+                self.print(&format!(
+                    "Error: unknown account {}. To add it, run:",
+                    account
+                ));
+                self.print(&format!("/set {} 123456789ABCDEF", option_name));
+                return;
+            },
+        };
 
         let token = if token.starts_with("${sec.data") {
             self.eval_string_expression(&token).map(Cow::into_owned)
@@ -70,13 +110,18 @@ impl Discord {
         };
         if let Some(t) = token {
             if !t.is_empty() {
-                discord::init(&self, &t, self.config.irc_mode.value());
-            } else {
+                discord::init(&self, account, &t, self.config.irc_mode.value());
+            } else if account == discord::DEFAULT_ACCOUNT {
                 self.print("Error: weecord.main.token is not set. To set it, run:");
                 self.print("/discord token 123456789ABCDEF");
+            } else {
+                self.print(&format!("Error: {} is not set", option_name));
             }
         } else {
-            self.print("Error: failed to evaluate token option, expected valid ${sec.data...}");
+            self.print(&format!(
+                "Error: failed to evaluate {}, expected valid ${{sec.data...}}",
+                option_name
+            ));
         }
     }
 }
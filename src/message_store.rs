@@ -0,0 +1,245 @@
+use crate::discord::DEFAULT_ACCOUNT;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serenity::model::{
+    channel::Message,
+    id::{ChannelId, MessageId},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+use weechat::Weechat;
+
+/// The maximum amount of messages kept on disk for a single channel
+const MAX_STORED_MESSAGES: usize = 5000;
+
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Message(Message),
+    Delete(MessageId),
+}
+
+/// An append only, on-disk log of messages, with one file per channel of every account
+///
+/// Edits and deletions are appended as new entries and resolved when the log is read back, the
+/// log is rewritten once it contains too many stale entries. The logs of the default account are
+/// kept in the store directory, those of other accounts in a directory named after the account.
+///
+/// Messages are stored from the main thread, reading is also safe from other threads as long as
+/// only the main thread rewrites logs.
+pub struct MessageStore {
+    dir: PathBuf,
+    /// A hash of the stored version of every message of the channels that have been written to,
+    /// so messages that are loaded again are not appended twice
+    ///
+    /// The hashes of a log are read on a background thread the first time it is written to,
+    /// messages stored in the meantime may be appended again.
+    stored: Arc<Mutex<HashMap<(String, ChannelId), HashMap<MessageId, u64>>>>,
+}
+
+fn hash_line(line: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Read a log, returning its messages oldest first and the amount of entries
+fn read_log(path: &Path) -> (Vec<Message>, usize) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return (Vec::new(), 0),
+    };
+
+    let mut entries = 0;
+    let mut messages = HashMap::new();
+    for line in BufReader::new(file).lines().filter_map(Result::ok) {
+        entries += 1;
+        match serde_json::from_str(&line) {
+            Ok(Entry::Message(msg)) => {
+                messages.insert(msg.id, msg);
+            },
+            Ok(Entry::Delete(id)) => {
+                messages.remove(&id);
+            },
+            Err(_) => {},
+        }
+    }
+
+    let mut messages: Vec<_> = messages.into_iter().map(|(_, msg)| msg).collect();
+    messages.sort_by_key(|msg| msg.id);
+
+    if messages.len() > MAX_STORED_MESSAGES {
+        messages.drain(..messages.len() - MAX_STORED_MESSAGES);
+    }
+    (messages, entries)
+}
+
+/// Hash the latest stored version of every message in a log
+fn read_hashes(path: &Path) -> HashMap<MessageId, u64> {
+    read_log(path)
+        .0
+        .into_iter()
+        .filter_map(|msg| {
+            let line = serde_json::to_string(&Entry::Message(msg.clone())).ok()?;
+            Some((msg.id, hash_line(&line)))
+        })
+        .collect()
+}
+
+impl MessageStore {
+    /// Open the message store in the weechat home directory, creating it if needed
+    pub fn new(weechat: &Weechat) -> Option<MessageStore> {
+        let home = weechat.info_get("weechat_dir", "")?;
+        let dir = PathBuf::from(home.as_ref())
+            .join("weecord")
+            .join("messages");
+        fs::create_dir_all(&dir).ok()?;
+
+        Some(MessageStore {
+            dir,
+            stored: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    fn account_dir(&self, account: &str) -> PathBuf {
+        if account == DEFAULT_ACCOUNT {
+            self.dir.clone()
+        } else {
+            self.dir.join(account)
+        }
+    }
+
+    fn path(&self, account: &str, channel: ChannelId) -> PathBuf {
+        self.account_dir(account).join(format!("{}.log", channel.0))
+    }
+
+    fn append(&self, account: &str, channel: ChannelId, line: &str) {
+        let _ = fs::create_dir_all(self.account_dir(account));
+        if let Ok(mut file) = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(account, channel))
+        {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    /// Store a new message, or a new version of an existing message
+    ///
+    /// Nothing is written if the message is already stored in the same version.
+    pub fn store(&self, account: &str, msg: &Message) {
+        let line = match serde_json::to_string(&Entry::Message(msg.clone())) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let hash = hash_line(&line);
+
+        let key = (account.to_owned(), msg.channel_id);
+        let mut stored = self.stored.lock();
+        if !stored.contains_key(&key) {
+            self.read_hashes_later(key.clone());
+        }
+        let channel = stored.entry(key).or_default();
+        if channel.insert(msg.id, hash) == Some(hash) {
+            return;
+        }
+        drop(stored);
+        self.append(account, msg.channel_id, &line);
+    }
+
+    /// Read the hashes of a log on a background thread, adding them to the hashes of the messages
+    /// that were stored in the meantime
+    fn read_hashes_later(&self, key: (String, ChannelId)) {
+        let path = self.path(&key.0, key.1);
+        let stored = Arc::clone(&self.stored);
+        thread::spawn(move || {
+            let hashes = read_hashes(&path);
+            if let Some(channel) = stored.lock().get_mut(&key) {
+                for (id, hash) in hashes {
+                    channel.entry(id).or_insert(hash);
+                }
+            }
+        });
+    }
+
+    /// Remove a message from the store
+    pub fn delete(&self, account: &str, channel: ChannelId, id: MessageId) {
+        if let Some(channel) = self.stored.lock().get_mut(&(account.to_owned(), channel)) {
+            channel.remove(&id);
+        }
+        if let Ok(line) = serde_json::to_string(&Entry::Delete(id)) {
+            self.append(account, channel, &line);
+        }
+    }
+
+    /// Load all stored messages for a channel, oldest first, without changing the log
+    pub fn load(&self, account: &str, channel: ChannelId) -> Vec<Message> {
+        read_log(&self.path(account, channel)).0
+    }
+
+    /// Like `load`, but also rewrite the log if it contains too many stale entries
+    ///
+    /// This must only be called from the main thread, where messages are stored.
+    pub fn load_and_compact(&self, account: &str, channel: ChannelId) -> Vec<Message> {
+        let (messages, entries) = read_log(&self.path(account, channel));
+        if entries > messages.len() * 2 {
+            self.compact(account, channel, &messages);
+            // Messages past the limit were dropped, so they can be stored again
+            self.stored.lock().remove(&(account.to_owned(), channel));
+        }
+        messages
+    }
+
+    /// Rewrite the log for a channel so it only contains `messages`
+    fn compact(&self, account: &str, channel: ChannelId, messages: &[Message]) {
+        let path = self.path(account, channel);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = match File::create(&tmp_path) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+
+        for msg in messages {
+            if let Ok(line) = serde_json::to_string(&Entry::Message(msg.clone())) {
+                if writeln!(file, "{}", line).is_err() {
+                    return;
+                }
+            }
+        }
+        let _ = fs::rename(tmp_path, path);
+    }
+
+    /// Search every stored channel of an account for messages containing `query`, newest first
+    pub fn search(&self, account: &str, query: &str, limit: usize) -> Vec<Message> {
+        let query = query.to_lowercase();
+        // Only files are logs, the directories of other accounts are skipped
+        let channels = match fs::read_dir(self.account_dir(account)) {
+            Ok(entries) => entries
+                .filter_map(Result::ok)
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    if path.extension()? != "log" {
+                        return None;
+                    }
+                    path.file_stem()?.to_str()?.parse().ok().map(ChannelId)
+                })
+                .collect::<Vec<_>>(),
+            Err(_) => return Vec::new(),
+        };
+
+        let mut results: Vec<_> = channels
+            .into_iter()
+            .flat_map(|channel| self.load(account, channel))
+            .filter(|msg| msg.content.to_lowercase().contains(&query))
+            .collect();
+        results.sort_by(|a, b| b.id.cmp(&a.id));
+        results.truncate(limit);
+        results
+    }
+}
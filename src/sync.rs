@@ -45,6 +45,16 @@ pub fn on_main<F: 'static + FnOnce(&Discord) + Send>(cb: F) {
     }
 }
 
+/// Queue a closure to run on the main thread, even when called from the main thread
+///
+/// Unlike `on_main`, this can be used while the plugin is still initializing
+pub fn on_main_later<F: 'static + FnOnce(&Discord) + Send>(cb: F) {
+    JOB_QUEUE
+        .lock()
+        .borrow_mut()
+        .push_back(Job::Nonblocking(Box::new(cb)));
+}
+
 pub fn on_main_blocking<R: Send, F: FnOnce(&Discord) -> R + Send, ER: 'static + Send>(cb: F) -> ER {
     let cb = unsafe {
         // This should be ok because the lifetime does not actually
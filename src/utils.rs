@@ -141,11 +141,19 @@ pub trait BufferExt {
     fn channel_id(&self) -> Option<ChannelId>;
     fn guild_id(&self) -> Option<GuildId>;
 
+    /// The account a buffer belongs to, other buffers use the default account
+    fn account(&self) -> String;
+    fn set_account(&self, account: &str);
+
     fn history_loaded(&self) -> bool;
     fn set_history_loaded(&self);
 
     fn nicks_loaded(&self) -> bool;
     fn set_nicks_loaded(&self);
+
+    /// A detached buffer shows older messages and does not follow new ones
+    fn detached(&self) -> bool;
+    fn set_detached(&self, detached: bool);
 }
 
 impl BufferExt for Buffer {
@@ -161,6 +169,16 @@ impl BufferExt for Buffer {
             .map(Into::into)
     }
 
+    fn account(&self) -> String {
+        self.get_localvar("account")
+            .map(Cow::into_owned)
+            .unwrap_or_else(crate::discord::default_account)
+    }
+
+    fn set_account(&self, account: &str) {
+        self.set_localvar("account", account);
+    }
+
     fn history_loaded(&self) -> bool {
         self.get_localvar("loaded_history").is_some()
     }
@@ -176,6 +194,14 @@ impl BufferExt for Buffer {
     fn set_nicks_loaded(&self) {
         self.set_localvar("loaded_nicks", "true");
     }
+
+    fn detached(&self) -> bool {
+        self.get_localvar("detached").map_or(false, |d| d == "1")
+    }
+
+    fn set_detached(&self, detached: bool) {
+        self.set_localvar("detached", &(detached as u8).to_string());
+    }
 }
 
 pub fn pins_for_channel(buffer: &MessageManager) -> Option<ChannelId> {
@@ -189,6 +215,30 @@ pub fn set_pins_for_channel(buffer: &MessageManager, channel: ChannelId) {
     buffer.set_localvar("pins_for_channel", &channel.0.to_string());
 }
 
+pub fn is_search_buffer(buffer: &Buffer) -> bool {
+    buffer.get_localvar("search_results").is_some()
+}
+
+pub fn set_search_buffer(buffer: &MessageManager) {
+    buffer.set_localvar("search_results", "true");
+}
+
+pub fn is_forum_buffer(buffer: &Buffer) -> bool {
+    buffer.get_localvar("forum").is_some()
+}
+
+pub fn set_forum_buffer(buffer: &MessageManager) {
+    buffer.set_localvar("forum", "true");
+}
+
+/// The channel a thread buffer belongs to, `None` if the buffer is not a thread
+pub fn parent_channel(buffer: &Buffer) -> Option<ChannelId> {
+    buffer
+        .get_localvar("parent_channel")
+        .and_then(|id| id.parse().ok())
+        .map(ChannelId)
+}
+
 /// Find the highest hoisted role (used for the user group) and the highest role (used for user coloring)
 pub fn find_highest_roles(cache: &CacheRwLock, member: &Member) -> Option<(Role, Role)> {
     let mut roles = member.roles(cache)?;
@@ -234,15 +284,28 @@ pub fn get_irc_mode(weechat: &weechat::Weechat) -> bool {
     crate::upgrade_plugin(weechat).config.irc_mode.value()
 }
 
-pub fn buffer_id_for_guild(id: GuildId) -> String {
-    format!("{}", id.0)
+/// The prefix of the buffer names of an account, buffers of the default account are not prefixed
+pub fn account_prefix(account: &str) -> String {
+    if account == crate::discord::DEFAULT_ACCOUNT {
+        String::new()
+    } else {
+        format!("{}.", account)
+    }
+}
+
+pub fn buffer_id_for_guild(account: &str, id: GuildId) -> String {
+    format!("{}{}", account_prefix(account), id.0)
 }
 
-pub fn buffer_id_for_channel(guild_id: Option<GuildId>, channel_id: ChannelId) -> String {
+pub fn buffer_id_for_channel(
+    account: &str,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+) -> String {
     if let Some(guild_id) = guild_id {
-        format!("{}.{}", guild_id, channel_id.0)
+        format!("{}{}.{}", account_prefix(account), guild_id, channel_id.0)
     } else {
-        format!("Private.{}", channel_id.0)
+        format!("{}Private.{}", account_prefix(account), channel_id.0)
     }
 }
 
@@ -352,6 +415,36 @@ pub fn get_users_nth_message(
         })
 }
 
+/// Send a message to a channel as a reply to another message
+///
+/// Discord includes `nonce` in the message it sends back, so it can be told apart from other
+/// messages with the same content.
+pub fn send_reply(
+    ctx: &Context,
+    channel: ChannelId,
+    guild: Option<GuildId>,
+    referenced: MessageId,
+    content: &str,
+    nonce: &str,
+) -> serenity::Result<Message> {
+    let mut reference = serde_json::json!({
+        "channel_id": channel.0.to_string(),
+        "message_id": referenced.0.to_string(),
+    });
+    if let Some(guild) = guild {
+        reference["guild_id"] = guild.0.to_string().into();
+    }
+
+    ctx.http.send_message(
+        channel.0,
+        &serde_json::json!({
+            "content": content,
+            "nonce": nonce,
+            "message_reference": reference,
+        }),
+    )
+}
+
 // TODO: Role mentions
 /// Parse user input and replace mentions with Discords internal representation
 ///
@@ -440,27 +533,84 @@ pub fn expand_guild_emojis(cache: &CacheRwLock, guild_id: Option<GuildId>, input
     }
 
     let emojis = EMOJI_SYNTAX.captures_iter(input);
-    if let Some(guild) = guild_id.and_then(|id| id.to_guild_cached(cache)) {
-        let guild = guild.read();
-        for emoji_match in emojis {
-            if let Some(prefix) = emoji_match.get(1) {
-                if prefix.as_str() == "\\" {
-                    continue;
-                }
+    for emoji_match in emojis {
+        if let Some(prefix) = emoji_match.get(1) {
+            if prefix.as_str() == "\\" {
+                continue;
             }
-            if let Some(emoji_match) = emoji_match.get(2) {
-                let emoji_name = emoji_match.as_str();
-                if let Some(guild_emoji) =
-                    guild.emojis.values().find(|emoji| emoji.name == emoji_name)
-                {
-                    out = out.replace(&format!(":{}:", emoji_name), &guild_emoji.mention());
-                }
+        }
+        if let Some(emoji_match) = emoji_match.get(2) {
+            let emoji_name = emoji_match.as_str();
+            if let Some(guild_emoji) = find_guild_emoji(cache, guild_id, emoji_name) {
+                out = out.replace(&format!(":{}:", emoji_name), &guild_emoji.mention());
             }
         }
     }
     out
 }
 
+/// Translate IRC formatting codes to markdown, escaping typed markdown if
+/// `weecord.main.escape_markdown` is enabled
+pub fn format_outgoing(weechat: &Weechat, input: &str) -> String {
+    let escape = crate::upgrade_plugin(weechat)
+        .config
+        .escape_markdown
+        .value();
+    parsing::irc_to_markdown(input, escape)
+}
+
+/// Find a custom emoji in a guild by name
+pub fn find_guild_emoji(
+    cache: &CacheRwLock,
+    guild_id: Option<GuildId>,
+    name: &str,
+) -> Option<Emoji> {
+    let guild = guild_id.and_then(|id| id.to_guild_cached(cache))?;
+    let guild = guild.read();
+    guild
+        .emojis
+        .values()
+        .find(|emoji| emoji.name == name)
+        .cloned()
+}
+
+/// Shortcodes Discord uses that differ from the GitHub shortcodes of the `emojis` crate
+const DISCORD_SHORTCODES: &[(&str, &str)] = &[
+    ("hugging", "🤗"),
+    ("nerd", "🤓"),
+    ("slight_frown", "🙁"),
+    ("slight_smile", "🙂"),
+    ("upside_down", "🙃"),
+];
+
+/// Resolve a `:name:` style emoji or a literal unicode emoji into a reaction, guild emojis take
+/// precedence over the builtin shortcodes
+pub fn resolve_reaction(
+    cache: &CacheRwLock,
+    guild_id: Option<GuildId>,
+    emoji: &str,
+) -> Option<ReactionType> {
+    if !(emoji.len() > 2 && emoji.starts_with(':') && emoji.ends_with(':')) {
+        return Some(ReactionType::Unicode(emoji.to_owned()));
+    }
+    let name = &emoji[1..emoji.len() - 1];
+
+    if let Some(guild_emoji) = find_guild_emoji(cache, guild_id, name) {
+        return Some(ReactionType::Custom {
+            animated: guild_emoji.animated,
+            id: guild_emoji.id,
+            name: Some(guild_emoji.name),
+        });
+    }
+
+    DISCORD_SHORTCODES
+        .iter()
+        .find(|(shortcode, _)| *shortcode == name)
+        .map(|(_, unicode)| *unicode)
+        .or_else(|| emojis::get_by_shortcode(name).map(emojis::Emoji::as_str))
+        .map(|unicode| ReactionType::Unicode(unicode.to_owned()))
+}
+
 /// Remove the guild id from global emojis
 pub fn clean_emojis(input: &str) -> String {
     let mut out = String::from(input);
@@ -1,4 +1,4 @@
-use crate::weechat_utils::message_manager::MessageManager;
+use crate::{message_store::MessageStore, weechat_utils::message_manager::MessageManager};
 use std::{cell::RefCell, collections::HashMap, sync::Arc};
 use weechat::Weechat;
 
@@ -6,23 +6,40 @@ use weechat::Weechat;
 pub struct BufferManager {
     weechat: Weechat,
     buffers: RefCell<HashMap<String, Arc<MessageManager>>>,
+    message_store: Option<Arc<MessageStore>>,
 }
 
 impl BufferManager {
-    pub(crate) fn new(weechat: Weechat) -> BufferManager {
+    pub(crate) fn new(weechat: Weechat, message_store: Option<Arc<MessageStore>>) -> BufferManager {
         BufferManager {
             weechat,
             buffers: RefCell::new(HashMap::new()),
+            message_store,
         }
     }
 
+    /// The on-disk message store, if it is enabled
+    pub fn message_store(&self) -> Option<Arc<MessageStore>> {
+        self.message_store.clone()
+    }
+
+    /// Every buffer that has been opened and not closed since
+    pub fn buffers(&self) -> Vec<Arc<MessageManager>> {
+        self.buffers
+            .borrow()
+            .iter()
+            .filter(|(name, _)| self.weechat.buffer_search("weecord", name).is_some())
+            .map(|(_, buffer)| Arc::clone(buffer))
+            .collect()
+    }
+
     pub fn get_buffer(&self, name: &str) -> Option<Arc<MessageManager>> {
         if let Some(buffer) = self.buffers.borrow().get(name) {
             return Some(Arc::clone(buffer));
         }
 
         if let Some(buffer) = self.weechat.buffer_search("weecord", name) {
-            let msg_manager = MessageManager::new(buffer);
+            let msg_manager = MessageManager::new(buffer, self.message_store.clone());
             self.buffers
                 .borrow_mut()
                 .insert(name.into(), Arc::new(msg_manager));
@@ -38,19 +55,22 @@ impl BufferManager {
         }
 
         if let Some(buffer) = self.weechat.buffer_search("weecord", name) {
-            let msg_manager = MessageManager::new(buffer);
+            let msg_manager = MessageManager::new(buffer, self.message_store.clone());
             self.buffers
                 .borrow_mut()
                 .insert(name.into(), Arc::new(msg_manager));
             Arc::clone(self.buffers.borrow().get(name).unwrap())
         } else {
-            let msg_manager = MessageManager::new(self.weechat.buffer_new::<(), ()>(
-                name,
-                Some(|_, b, i| crate::hook::buffer_input(b, &i)),
-                None,
-                None,
-                None,
-            ));
+            let msg_manager = MessageManager::new(
+                self.weechat.buffer_new::<(), ()>(
+                    name,
+                    Some(|_, b, i| crate::hook::buffer_input(b, &i)),
+                    None,
+                    None,
+                    None,
+                ),
+                self.message_store.clone(),
+            );
             self.buffers
                 .borrow_mut()
                 .insert(name.into(), Arc::new(msg_manager));
@@ -0,0 +1,161 @@
+//! A config section where options can be added with `/set` and removed with `/unset`, which
+//! rust-weechat does not wrap
+//!
+//! This is used for the `weecord.account` section, where every named account has a
+//! `weecord.account.<name>.token` option.
+
+use super::cstr;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeSet,
+    ffi::CStr,
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+    sync::Arc,
+};
+use weechat::Weechat;
+use weechat_sys::{t_config_file, t_config_option, t_config_section, t_weechat_plugin};
+
+const OPTION_SET_ERROR: c_int = 0;
+const OPTION_SET_OK_CHANGED: c_int = 2;
+const OPTION_UNSET_OK_REMOVED: c_int = 2;
+
+lazy_static! {
+    static ref ACCOUNTS: Arc<Mutex<BTreeSet<String>>> = Arc::new(Mutex::new(BTreeSet::new()));
+}
+
+/// The names of all accounts with a token option, sorted
+pub fn accounts() -> Vec<String> {
+    ACCOUNTS.lock().iter().cloned().collect()
+}
+
+/// The account an option belongs to, only `<name>.token` options are allowed
+fn account_of_option(name: &str) -> Option<&str> {
+    let mut parts = name.splitn(2, '.');
+    let account = parts.next()?;
+    if account.is_empty() || parts.next()? != "token" {
+        return None;
+    }
+    Some(account)
+}
+
+unsafe extern "C" fn create_option(
+    pointer: *const c_void,
+    _data: *mut c_void,
+    config_file: *mut t_config_file,
+    section: *mut t_config_section,
+    option_name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    let plugin = pointer as *mut t_weechat_plugin;
+    let name = CStr::from_ptr(option_name).to_string_lossy();
+    let account = match account_of_option(&name) {
+        Some(account) => account.to_owned(),
+        None => return OPTION_SET_ERROR,
+    };
+
+    let search_option = (*plugin).config_search_option.unwrap();
+    let existing = search_option(config_file, section, option_name);
+    if !existing.is_null() {
+        let option_set = (*plugin).config_option_set.unwrap();
+        return option_set(existing, value, 1);
+    }
+
+    let new_option = (*plugin).config_new_option.unwrap();
+    let description = cstr(&format!(
+        "Discord auth token of the account {}. Supports secure data",
+        account
+    ));
+    let option = new_option(
+        config_file,
+        section,
+        option_name,
+        cstr("string").as_ptr(),
+        description.as_ptr(),
+        ptr::null(),
+        0,
+        0,
+        cstr("").as_ptr(),
+        value,
+        0,
+        None,
+        ptr::null(),
+        ptr::null_mut(),
+        None,
+        ptr::null(),
+        ptr::null_mut(),
+        None,
+        ptr::null(),
+        ptr::null_mut(),
+    );
+    if option.is_null() {
+        return OPTION_SET_ERROR;
+    }
+
+    ACCOUNTS.lock().insert(account);
+    OPTION_SET_OK_CHANGED
+}
+
+unsafe extern "C" fn delete_option(
+    pointer: *const c_void,
+    _data: *mut c_void,
+    _config_file: *mut t_config_file,
+    _section: *mut t_config_section,
+    option: *mut t_config_option,
+) -> c_int {
+    let plugin = pointer as *mut t_weechat_plugin;
+
+    let get_string = (*plugin).config_option_get_string.unwrap();
+    let name = get_string(option, cstr("name").as_ptr());
+    if !name.is_null() {
+        let name = CStr::from_ptr(name).to_string_lossy();
+        if let Some(account) = account_of_option(&name) {
+            ACCOUNTS.lock().remove(account);
+        }
+    }
+
+    let option_free = (*plugin).config_option_free.unwrap();
+    option_free(option);
+    OPTION_UNSET_OK_REMOVED
+}
+
+/// Add the `account` section to the config file containing `option`
+///
+/// This must be called before the config file is read, so the saved accounts are created.
+pub fn create_account_section(weechat: &Weechat, option: &str) {
+    let plugin = weechat.as_ptr();
+
+    unsafe {
+        let config_get = (*plugin).config_get.unwrap();
+        let option = config_get(cstr(option).as_ptr());
+        if option.is_null() {
+            return;
+        }
+        let get_pointer = (*plugin).config_option_get_pointer.unwrap();
+        let config_file = get_pointer(option, cstr("config_file").as_ptr()) as *mut t_config_file;
+
+        let new_section = (*plugin).config_new_section.unwrap();
+        new_section(
+            config_file,
+            cstr("account").as_ptr(),
+            1,
+            1,
+            None,
+            ptr::null(),
+            ptr::null_mut(),
+            None,
+            ptr::null(),
+            ptr::null_mut(),
+            None,
+            ptr::null(),
+            ptr::null_mut(),
+            Some(create_option),
+            plugin as *const c_void,
+            ptr::null_mut(),
+            Some(delete_option),
+            plugin as *const c_void,
+            ptr::null_mut(),
+        );
+    }
+}
@@ -0,0 +1,256 @@
+//! Edit printed lines in place through weechat's hdata api, which rust-weechat does not wrap
+//!
+//! Lines are found by the `discord_msg_<id>` tag every message is printed with, or by the
+//! `discord_pending_<id>` tag of the local echo of a message that is being sent. Lines can't be
+//! removed, so they are hidden by clearing their `displayed` flag instead.
+
+use super::cstr;
+use serenity::model::id::MessageId;
+use std::{
+    ffi::CStr,
+    os::raw::{c_char, c_int, c_void},
+};
+use weechat::Weechat;
+use weechat_sys::{t_hdata, t_weechat_plugin};
+
+/// The `line_data` of each line a message was printed on, oldest first
+pub struct MessageLines {
+    plugin: *mut t_weechat_plugin,
+    buffer: *mut c_void,
+    line_data: Vec<*mut c_void>,
+}
+
+unsafe fn hdata_get(plugin: *mut t_weechat_plugin, name: &str) -> *mut t_hdata {
+    let hdata_get = (*plugin).hdata_get.unwrap();
+    hdata_get(plugin, cstr(name).as_ptr())
+}
+
+unsafe fn hdata_pointer(
+    plugin: *mut t_weechat_plugin,
+    hdata: *mut t_hdata,
+    pointer: *mut c_void,
+    name: &str,
+) -> *mut c_void {
+    let hdata_pointer = (*plugin).hdata_pointer.unwrap();
+    hdata_pointer(hdata, pointer, cstr(name).as_ptr())
+}
+
+unsafe fn hdata_string(
+    plugin: *mut t_weechat_plugin,
+    hdata: *mut t_hdata,
+    pointer: *mut c_void,
+    name: &str,
+) -> Option<String> {
+    let hdata_string = (*plugin).hdata_string.unwrap();
+    let value: *const c_char = hdata_string(hdata, pointer, cstr(name).as_ptr());
+    if value.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+    }
+}
+
+/// Tag added to lines that were hidden
+pub const HIDDEN_TAG: &str = "discord_hidden";
+
+impl MessageLines {
+    /// Find the lines of a message in a weecord buffer
+    pub fn find(weechat: &Weechat, buffer_name: &str, id: MessageId) -> MessageLines {
+        MessageLines::find_tagged(weechat, buffer_name, &format!("discord_msg_{}", id.0))
+    }
+
+    /// Find the most recent contiguous lines with a tag in a weecord buffer
+    pub fn find_tagged(weechat: &Weechat, buffer_name: &str, tag: &str) -> MessageLines {
+        let plugin = weechat.as_ptr();
+        let mut line_data = Vec::new();
+
+        unsafe {
+            let buffer_search = (*plugin).buffer_search.unwrap();
+            let buffer =
+                buffer_search(cstr("weecord").as_ptr(), cstr(buffer_name).as_ptr()) as *mut c_void;
+            if buffer.is_null() {
+                return MessageLines {
+                    plugin,
+                    buffer,
+                    line_data,
+                };
+            }
+
+            let buffer_hdata = hdata_get(plugin, "buffer");
+            let lines_hdata = hdata_get(plugin, "lines");
+            let line_hdata = hdata_get(plugin, "line");
+            let line_data_hdata = hdata_get(plugin, "line_data");
+            let hdata_integer = (*plugin).hdata_integer.unwrap();
+            let hdata_move = (*plugin).hdata_move.unwrap();
+
+            let lines = hdata_pointer(plugin, buffer_hdata, buffer, "own_lines");
+            let mut line = hdata_pointer(plugin, lines_hdata, lines, "last_line");
+            let mut found = false;
+
+            // Recent messages are the most likely to change, so search from the end
+            while !line.is_null() {
+                let data = hdata_pointer(plugin, line_hdata, line, "data");
+                let tags_count = hdata_integer(line_data_hdata, data, cstr("tags_count").as_ptr());
+                let tagged = (0..tags_count).any(|i| {
+                    hdata_string(plugin, line_data_hdata, data, &format!("{}|tags_array", i))
+                        .map_or(false, |t| t == tag)
+                });
+
+                if tagged {
+                    found = true;
+                    line_data.push(data);
+                } else if found {
+                    // The lines of a message are contiguous
+                    break;
+                }
+                line = hdata_move(line_hdata, line, -1);
+            }
+        }
+
+        line_data.reverse();
+        MessageLines {
+            plugin,
+            buffer,
+            line_data,
+        }
+    }
+
+    /// Hide the lines by clearing their `displayed` flag, the way a filter hides lines
+    ///
+    /// weechat sets the flag again when filters are changed, which shows the lines again.
+    pub fn hide(&self) -> bool {
+        if self.line_data.is_empty() {
+            return false;
+        }
+
+        unsafe {
+            let plugin = self.plugin;
+            let buffer_hdata = hdata_get(plugin, "buffer");
+            let lines_hdata = hdata_get(plugin, "lines");
+            let line_data_hdata = hdata_get(plugin, "line_data");
+            let hdata_get_var_offset = (*plugin).hdata_get_var_offset.unwrap();
+
+            let displayed = hdata_get_var_offset(line_data_hdata, cstr("displayed").as_ptr());
+            let lines_hidden = hdata_get_var_offset(lines_hdata, cstr("lines_hidden").as_ptr());
+            if displayed < 0 || lines_hidden < 0 {
+                return false;
+            }
+
+            // Merged buffers share the line data, so this hides the lines there too
+            for &data in &self.line_data {
+                *(data as *mut c_char).offset(displayed as isize) = 0;
+            }
+            // Lets the status bar show that the buffer has hidden lines
+            for name in &["own_lines", "lines"] {
+                let lines = hdata_pointer(plugin, buffer_hdata, self.buffer, name);
+                if !lines.is_null() {
+                    *((lines as *mut c_char).offset(lines_hidden as isize) as *mut c_int) = 1;
+                }
+            }
+        }
+
+        // Updating the lines makes weechat redraw the buffer
+        self.add_tag(HIDDEN_TAG)
+    }
+
+    /// Add a tag to the lines, weechat applies its filters to them again
+    pub fn add_tag(&self, tag: &str) -> bool {
+        if self.line_data.is_empty() {
+            return false;
+        }
+
+        unsafe {
+            let plugin = self.plugin;
+            let line_data_hdata = hdata_get(plugin, "line_data");
+            let hdata_integer = (*plugin).hdata_integer.unwrap();
+            let hashtable_new = (*plugin).hashtable_new.unwrap();
+            let hashtable_set = (*plugin).hashtable_set.unwrap();
+            let hashtable_free = (*plugin).hashtable_free.unwrap();
+            let hdata_update = (*plugin).hdata_update.unwrap();
+
+            for &data in &self.line_data {
+                let tags_count = hdata_integer(line_data_hdata, data, cstr("tags_count").as_ptr());
+                let mut tags: Vec<_> = (0..tags_count)
+                    .filter_map(|i| {
+                        hdata_string(plugin, line_data_hdata, data, &format!("{}|tags_array", i))
+                    })
+                    .collect();
+                tags.push(tag.to_owned());
+
+                let hashtable = hashtable_new(
+                    8,
+                    cstr("string").as_ptr(),
+                    cstr("string").as_ptr(),
+                    None,
+                    None,
+                );
+                if hashtable.is_null() {
+                    return false;
+                }
+                let (key, value) = (cstr("tags_array"), cstr(&tags.join(",")));
+                hashtable_set(
+                    hashtable,
+                    key.as_ptr() as *const c_void,
+                    value.as_ptr() as *const c_void,
+                );
+                let updated = hdata_update(line_data_hdata, data, hashtable);
+                hashtable_free(hashtable);
+                if updated == 0 {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Replace the lines with a new rendering of the message, the amount of lines must not change
+    ///
+    /// Only the first line has a prefix, like when a multi-line message is printed.
+    pub fn update(&self, prefix: &str, content: &str, tags: &str) -> bool {
+        let new_lines: Vec<_> = content.split('\n').collect();
+        if new_lines.len() != self.line_data.len() {
+            return false;
+        }
+
+        unsafe {
+            let plugin = self.plugin;
+            let line_data_hdata = hdata_get(plugin, "line_data");
+            let hashtable_new = (*plugin).hashtable_new.unwrap();
+            let hashtable_set = (*plugin).hashtable_set.unwrap();
+            let hashtable_free = (*plugin).hashtable_free.unwrap();
+            let hdata_update = (*plugin).hdata_update.unwrap();
+
+            for (index, (&data, message)) in self.line_data.iter().zip(new_lines).enumerate() {
+                let hashtable = hashtable_new(
+                    8,
+                    cstr("string").as_ptr(),
+                    cstr("string").as_ptr(),
+                    None,
+                    None,
+                );
+                if hashtable.is_null() {
+                    return false;
+                }
+
+                let values = [
+                    (cstr("prefix"), cstr(if index == 0 { prefix } else { "" })),
+                    (cstr("message"), cstr(message)),
+                    (cstr("tags_array"), cstr(tags)),
+                ];
+                for (key, value) in &values {
+                    hashtable_set(
+                        hashtable,
+                        key.as_ptr() as *const c_void,
+                        value.as_ptr() as *const c_void,
+                    );
+                }
+                let updated = hdata_update(line_data_hdata, data, hashtable);
+                hashtable_free(hashtable);
+                if updated == 0 {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
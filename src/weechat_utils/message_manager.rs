@@ -1,65 +1,680 @@
-use crate::utils::BufferExt;
+use super::{hdata::MessageLines, window};
+use crate::{
+    config::DeletedMessages,
+    message_store::MessageStore,
+    utils::{BufferExt, ChannelExt},
+};
 use serenity::{
     cache::CacheRwLock,
     model::{
-        channel::Message,
-        id::{MessageId, UserId},
+        channel::{Message, MessageReaction},
+        id::{ChannelId, MessageId, UserId},
     },
 };
-use std::{cell::RefCell, ops::Deref, sync::Arc};
-use weechat::Buffer;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ops::Deref,
+    sync::Arc,
+};
+use weechat::{buffer::HotlistPriority, Buffer};
+
+/// The local echo of a message that is waiting in the send queue
+struct PendingMessage {
+    id: u64,
+    /// Sent with the message, Discord includes it in the message it sends back
+    nonce: String,
+    prefix: String,
+    echo: String,
+    failed: bool,
+}
 
 /// MessageRenderer wraps a weechat buffer and facilitates editing the buffer and drawing the
 /// messages
 pub struct MessageManager {
     buffer: Buffer,
     messages: Arc<RefCell<Vec<Message>>>,
+    /// Previous versions of edited messages, oldest first
+    edits: Arc<RefCell<HashMap<MessageId, Vec<Message>>>>,
+    /// Messages that have been deleted but are still shown, see `weecord.look.deleted_messages`
+    deleted: Arc<RefCell<HashSet<MessageId>>>,
+    /// Whether spoilers are shown in the whole buffer
+    spoilers_shown: RefCell<bool>,
+    /// Messages whose spoilers are shown or hidden unlike the rest of the buffer
+    spoilers_toggled: RefCell<HashSet<MessageId>>,
+    store: Option<Arc<MessageStore>>,
+    marked: RefCell<Option<MessageId>>,
+    pending: RefCell<Vec<PendingMessage>>,
+    /// Messages that were received while the buffer was detached
+    unseen: RefCell<usize>,
+    /// How many messages were deleted at once, printed after the newest message at the time
+    bulk_deletes: RefCell<Vec<(Option<MessageId>, usize)>>,
+    /// The newest message when the connection was lost, missed messages are fetched after it
+    missed_after: RefCell<Option<MessageId>>,
 }
 
 impl MessageManager {
-    /// Create a new MessageManager from a buffer
-    pub fn new(buffer: Buffer) -> MessageManager {
+    /// Create a new MessageManager from a buffer, messages will be written through to `store` if
+    /// it is provided
+    pub fn new(buffer: Buffer, store: Option<Arc<MessageStore>>) -> MessageManager {
         MessageManager {
             buffer,
             messages: Arc::new(RefCell::new(Vec::new())),
+            edits: Arc::new(RefCell::new(HashMap::new())),
+            deleted: Arc::new(RefCell::new(HashSet::new())),
+            spoilers_shown: RefCell::new(false),
+            spoilers_toggled: RefCell::new(HashSet::new()),
+            store,
+            marked: RefCell::new(None),
+            pending: RefCell::new(Vec::new()),
+            unseen: RefCell::new(0),
+            bulk_deletes: RefCell::new(Vec::new()),
+            missed_after: RefCell::new(None),
         }
     }
 
-    /// Format and print message to the buffer
-    fn print_msg(&self, cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<UserId> {
+    /// Get the message store, if the message belongs to the channel of this buffer
+    fn store_for(&self, channel: ChannelId) -> Option<&MessageStore> {
+        if self.buffer.channel_id() == Some(channel) {
+            self.store.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Format a message, returning the prefix, content, tags and unknown users
+    fn render(
+        &self,
+        cache: &CacheRwLock,
+        msg: &Message,
+        notify: bool,
+    ) -> (String, String, Vec<String>, Vec<UserId>) {
         let weechat = self.buffer.get_weechat();
         let maybe_guild = self.buffer.guild_id();
-        let (prefix, content, unknown_users) =
-            formatting_utils::render_msg(cache, &weechat, msg, maybe_guild);
+        let show_spoilers = self.spoilers_shown_for(&msg.id);
+        let (prefix, mut content, unknown_users) = formatting_utils::render_msg(
+            cache,
+            &weechat,
+            &self.buffer.account(),
+            msg,
+            maybe_guild,
+            show_spoilers,
+        );
+        let mut tags = formatting_utils::msg_tags(cache, msg, notify);
+        if self.deleted.borrow().contains(&msg.id) {
+            let mode = crate::upgrade_plugin(&weechat).config.deleted_messages();
+            content = formatting_utils::render_deleted(
+                cache,
+                &weechat,
+                msg,
+                maybe_guild,
+                mode,
+                show_spoilers,
+            );
+            tags.push("discord_deleted".to_owned());
+        }
+        if *self.marked.borrow() == Some(msg.id) {
+            content = format!(
+                "{}▶{} {}",
+                weechat.color("yellow"),
+                weechat.color("reset"),
+                content
+            );
+        }
+        if crate::upgrade_plugin(&weechat).config.inline_edits.value() {
+            if let Some(previous) = self.edits.borrow().get(&msg.id).and_then(|v| v.last()) {
+                content = content
+                    + "\n"
+                    + &formatting_utils::render_edit_diff(
+                        &weechat,
+                        &previous.content,
+                        &msg.content,
+                        show_spoilers,
+                    );
+            }
+        }
+        if let Some(referenced) = Self::referenced_id(msg) {
+            let quote = match self.get_message(&referenced) {
+                Some(referenced) => formatting_utils::render_reply_quote(
+                    cache,
+                    &weechat,
+                    &referenced,
+                    maybe_guild,
+                    self.spoilers_shown_for(&referenced.id),
+                ),
+                None => formatting_utils::render_unknown_reply_quote(&weechat),
+            };
+            content = quote + "\n" + &content;
+        }
+        (prefix, content, tags, unknown_users)
+    }
+
+    /// Format and print message to the buffer, returning the unknown users and the amount of
+    /// lines printed
+    fn print_msg(&self, cache: &CacheRwLock, msg: &Message, notify: bool) -> (Vec<UserId>, usize) {
+        let (prefix, content, tags, unknown_users) = self.render(cache, msg, notify);
         self.buffer.print_tags_dated(
             msg.timestamp.timestamp(),
-            &formatting_utils::msg_tags(cache, msg, notify).join(","),
+            &tags.join(","),
             &format!("{}\t{}", prefix, content),
         );
-        unknown_users
+        (unknown_users, content.lines().count().max(1))
+    }
+
+    /// Add a numbered search result, showing the channel it was sent in
+    pub fn add_search_result(&self, cache: &CacheRwLock, msg: &Message, n: usize) {
+        let weechat = self.buffer.get_weechat();
+        let guild = msg.guild_id.or_else(|| self.buffer.guild_id());
+        let (prefix, content, _) = formatting_utils::render_msg(
+            cache,
+            &weechat,
+            &self.buffer.account(),
+            msg,
+            guild,
+            false,
+        );
+        let channel_name = msg
+            .channel_id
+            .to_channel_cached(cache)
+            .map(|channel| channel.name())
+            .unwrap_or_else(|| msg.channel_id.0.to_string());
+
+        self.buffer.print_tags_dated(
+            msg.timestamp.timestamp(),
+            &formatting_utils::msg_tags(cache, msg, false).join(","),
+            &format!(
+                "{}\t{}[{}] #{}{} {}",
+                prefix,
+                weechat.color("8"),
+                n,
+                channel_name,
+                weechat.color("reset"),
+                content
+            ),
+        );
+        self.messages.borrow_mut().push(msg.clone());
+    }
+
+    /// Redraw a single message by editing its lines, returns false if the amount of lines changed
+    /// and the whole buffer has to be redrawn instead
+    fn redraw_message(&self, cache: &CacheRwLock, msg: &Message) -> bool {
+        let (prefix, content, tags, _) = self.render(cache, msg, false);
+        MessageLines::find(&self.buffer.get_weechat(), &self.buffer.get_name(), msg.id).update(
+            &prefix,
+            &content,
+            &tags.join(","),
+        )
+    }
+
+    /// Change the reactions of a printed message and redraw it
+    pub fn update_reactions(
+        &self,
+        cache: &CacheRwLock,
+        id: &MessageId,
+        update: impl FnOnce(&mut Vec<MessageReaction>),
+    ) {
+        let msg = match self
+            .messages
+            .borrow_mut()
+            .iter_mut()
+            .find(|msg| &msg.id == id)
+        {
+            Some(msg) => {
+                update(&mut msg.reactions);
+                msg.clone()
+            },
+            None => return,
+        };
+        if let Some(store) = self.store_for(msg.channel_id) {
+            store.store(&self.buffer.account(), &msg);
+        }
+        if !self.redraw_message(cache, &msg) {
+            self.redraw_buffer(cache);
+        }
+    }
+
+    /// Redraw a printed message, for when something it is rendered with has changed
+    pub fn refresh_message(&self, cache: &CacheRwLock, id: &MessageId) {
+        if let Some(msg) = self.get_message(id) {
+            if !self.redraw_message(cache, &msg) {
+                self.redraw_buffer(cache);
+            }
+        }
     }
 
     /// Clear the buffer and reprint all messages
     pub fn redraw_buffer(&self, cache: &CacheRwLock) {
+        self.redraw_buffer_notifying(cache, &HashSet::new());
+    }
+
+    /// Clear the buffer and reprint all messages, the messages in `notify` notify like new ones,
+    /// returning the unknown users
+    fn redraw_buffer_notifying(
+        &self,
+        cache: &CacheRwLock,
+        notify: &HashSet<MessageId>,
+    ) -> Vec<UserId> {
         self.buffer.clear();
+        let mut unknown_users = Vec::new();
+        let bulk_deletes = self.bulk_deletes.borrow();
+        let mut next_delete = 0;
         for message in self.messages.borrow().iter() {
-            self.print_msg(cache, &message, false);
+            while next_delete < bulk_deletes.len() && bulk_deletes[next_delete].0 < Some(message.id)
+            {
+                self.print_bulk_delete(bulk_deletes[next_delete].1);
+                next_delete += 1;
+            }
+            let (users, _) = self.print_msg(cache, &message, notify.contains(&message.id));
+            unknown_users.extend(users);
         }
+        for &(_, count) in &bulk_deletes[next_delete..] {
+            self.print_bulk_delete(count);
+        }
+        for pending in self.pending.borrow().iter() {
+            self.print_pending(pending);
+        }
+        unknown_users
     }
 
     /// Removes all content from the buffer
     pub fn clear(&self) {
         self.messages.borrow_mut().clear();
+        self.pending.borrow_mut().clear();
+        self.bulk_deletes.borrow_mut().clear();
+        *self.unseen.borrow_mut() = 0;
+        *self.missed_after.borrow_mut() = None;
         self.buffer.clear();
     }
 
+    /// Count a message that was received while the buffer is detached
+    ///
+    /// The message is not printed, instead a notice at the end of the buffer says how many
+    /// messages are waiting, and the buffer is added to the hotlist like the message would have.
+    pub fn add_unseen_message(&self, cache: &CacheRwLock, msg: &Message, notify: bool) {
+        let count = {
+            let mut unseen = self.unseen.borrow_mut();
+            *unseen += 1;
+            *unseen
+        };
+        let weechat = self.buffer.get_weechat();
+        let prefix = weechat.get_prefix("network");
+        let content = format!(
+            "{} new message{} below, use /discord goto latest to show {}",
+            count,
+            if count == 1 { "" } else { "s" },
+            if count == 1 { "it" } else { "them" }
+        );
+        let notify_tag = formatting_utils::msg_tags(cache, msg, notify)
+            .into_iter()
+            .find(|tag| tag.starts_with("notify_"))
+            .unwrap_or_else(|| "notify_none".to_owned());
+
+        // Printing the first notice notifies and highlights like the message would
+        if count == 1 {
+            self.buffer.print_tags_dated(
+                0,
+                &format!("discord_unseen,no_log,{}", notify_tag),
+                &format!("{}\t{}", prefix, content),
+            );
+            return;
+        }
+
+        MessageLines::find_tagged(&weechat, &self.buffer.get_name(), "discord_unseen").update(
+            &prefix,
+            &content,
+            "discord_unseen,no_log,notify_none",
+        );
+        let priority = match notify_tag.as_str() {
+            "notify_highlight" => HotlistPriority::Highlight,
+            "notify_private" => HotlistPriority::Private,
+            "notify_message" => HotlistPriority::Message,
+            _ => return,
+        };
+        self.buffer.set_hotlist(priority);
+    }
+
+    fn pending_tag(id: u64) -> String {
+        format!("discord_pending_{}", id)
+    }
+
+    fn pending_content(&self, pending: &PendingMessage) -> String {
+        let weechat = self.buffer.get_weechat();
+        let (color, marker) = if pending.failed {
+            ("red", "failed")
+        } else {
+            ("8", "sending")
+        };
+        format!(
+            "{} {}({}){}",
+            pending.echo,
+            weechat.color(color),
+            marker,
+            weechat.color("reset")
+        )
+    }
+
+    fn pending_tags(id: u64) -> String {
+        format!("{},notify_none,no_log,self_msg", Self::pending_tag(id))
+    }
+
+    fn print_pending(&self, pending: &PendingMessage) {
+        self.buffer.print_tags_dated(
+            0,
+            &Self::pending_tags(pending.id),
+            &format!("{}\t{}", pending.prefix, self.pending_content(pending)),
+        );
+    }
+
+    /// Print the local echo of a queued message, marked as being sent
+    ///
+    /// `echo` is how the message is shown until it is replaced by the message Discord sends back,
+    /// which is recognized by its `nonce`.
+    pub fn add_pending(&self, id: u64, nonce: &str, prefix: &str, echo: &str) {
+        let pending = PendingMessage {
+            id,
+            nonce: nonce.to_owned(),
+            prefix: prefix.to_owned(),
+            echo: echo.to_owned(),
+            failed: false,
+        };
+        self.print_pending(&pending);
+        self.pending.borrow_mut().push(pending);
+    }
+
+    /// Mark the local echo of a queued message as failed, or as being sent again
+    pub fn set_pending_failed(&self, id: u64, failed: bool) {
+        let mut pending = self.pending.borrow_mut();
+        let pending = match pending.iter_mut().find(|pending| pending.id == id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        pending.failed = failed;
+        MessageLines::find_tagged(
+            &self.buffer.get_weechat(),
+            &self.buffer.get_name(),
+            &Self::pending_tag(id),
+        )
+        .update(
+            &pending.prefix,
+            &self.pending_content(pending),
+            &Self::pending_tags(id),
+        );
+    }
+
+    /// Remove the local echo of a failed message that can no longer be retried
+    pub fn discard_pending(&self, id: u64) {
+        let index = self.pending.borrow().iter().position(|it| it.id == id);
+        if let Some(index) = index {
+            self.pending.borrow_mut().remove(index);
+            MessageLines::find_tagged(
+                &self.buffer.get_weechat(),
+                &self.buffer.get_name(),
+                &Self::pending_tag(id),
+            )
+            .hide();
+        }
+    }
+
+    /// Replace the local echo of a queued message with the message that was sent
+    pub fn confirm_pending(&self, cache: &CacheRwLock, id: u64, msg: &Message) {
+        let index = self.pending.borrow().iter().position(|it| it.id == id);
+        let pending = match index {
+            Some(index) => self.pending.borrow_mut().remove(index),
+            None => return,
+        };
+        if self.get_message(&msg.id).is_some() {
+            // The message was received before the echo could be matched, drop the echo
+            self.redraw_buffer(cache);
+        } else {
+            self.replace_pending(cache, &pending, msg);
+        }
+    }
+
+    fn replace_pending(&self, cache: &CacheRwLock, pending: &PendingMessage, msg: &Message) {
+        self.messages.borrow_mut().push(msg.clone());
+        if let Some(store) = self.store_for(msg.channel_id) {
+            store.store(&self.buffer.account(), msg);
+        }
+        let (prefix, content, tags, _) = self.render(cache, msg, false);
+        let updated = MessageLines::find_tagged(
+            &self.buffer.get_weechat(),
+            &self.buffer.get_name(),
+            &Self::pending_tag(pending.id),
+        )
+        .update(&prefix, &content, &tags.join(","));
+        if !updated {
+            self.redraw_buffer(cache);
+        }
+    }
+
+    /// Set the message that is highlighted as the target of a jump
+    pub fn set_marked_message(&self, id: Option<MessageId>) {
+        *self.marked.borrow_mut() = id;
+    }
+
+    /// Get a printed message by id
+    pub fn get_message(&self, id: &MessageId) -> Option<Message> {
+        self.messages
+            .borrow()
+            .iter()
+            .find(|it| &it.id == id)
+            .cloned()
+    }
+
+    /// Get a printed message by its position, starting from the oldest message
+    pub fn message_at(&self, index: usize) -> Option<Message> {
+        self.messages.borrow().get(index).cloned()
+    }
+
+    /// Get the id of the newest printed message, including deleted messages
+    pub fn last_message_id(&self) -> Option<MessageId> {
+        self.messages.borrow().iter().map(|msg| msg.id).max()
+    }
+
+    /// Remember the newest message when the connection is lost
+    ///
+    /// Messages that arrive once the connection is back are printed before the missed messages
+    /// have been fetched, so the newest message at that point can't be used.
+    pub fn save_missed_after(&self) {
+        *self.missed_after.borrow_mut() = self.last_message_id();
+    }
+
+    pub fn take_missed_after(&self) -> Option<MessageId> {
+        self.missed_after.borrow_mut().take()
+    }
+
+    /// Get the nth most recent printed message that has not been deleted, starting from 1
+    pub fn nth_message(&self, n: usize) -> Option<Message> {
+        if n == 0 {
+            return None;
+        }
+        let deleted = self.deleted.borrow();
+        self.messages
+            .borrow()
+            .iter()
+            .rev()
+            .filter(|msg| !deleted.contains(&msg.id))
+            .nth(n - 1)
+            .cloned()
+    }
+
+    /// Get the previous versions of a message that were seen being edited, oldest first
+    pub fn edit_history(&self, id: &MessageId) -> Vec<Message> {
+        self.edits.borrow().get(id).cloned().unwrap_or_default()
+    }
+
+    /// Show or hide the spoilers of a single message, or of the whole buffer if `id` is `None`
+    ///
+    /// Messages are redrawn from the stored copies, nothing is fetched again.
+    pub fn set_spoilers_shown(&self, cache: &CacheRwLock, id: Option<&MessageId>, shown: bool) {
+        match id {
+            Some(id) => {
+                let msg = match self.get_message(id) {
+                    Some(msg) => msg,
+                    None => return,
+                };
+                if *self.spoilers_shown.borrow() == shown {
+                    self.spoilers_toggled.borrow_mut().remove(id);
+                } else {
+                    self.spoilers_toggled.borrow_mut().insert(*id);
+                }
+                if !self.redraw_message(cache, &msg) {
+                    self.redraw_buffer(cache);
+                }
+            },
+            None => {
+                *self.spoilers_shown.borrow_mut() = shown;
+                self.spoilers_toggled.borrow_mut().clear();
+                self.redraw_buffer(cache);
+            },
+        }
+    }
+
+    /// Whether the spoilers of a message are shown, either because it was toggled or because
+    /// they are shown in the whole buffer
+    pub fn spoilers_shown_for(&self, id: &MessageId) -> bool {
+        *self.spoilers_shown.borrow() != self.spoilers_toggled.borrow().contains(id)
+    }
+
+    /// Get the id of the message that `msg` is replying to
+    ///
+    /// Both gateway and REST payloads carry the reference, so this works for replies from
+    /// anyone, including messages loaded from the history. Crossposts also carry a reference,
+    /// to a message in another channel, which is not quoted.
+    fn referenced_id(msg: &Message) -> Option<MessageId> {
+        msg.message_reference
+            .as_ref()
+            .filter(|reference| reference.channel_id == msg.channel_id)
+            .and_then(|reference| reference.message_id)
+    }
+
     /// Add a message to the end of a buffer (chronologically)
+    ///
+    /// Messages that are already shown are skipped, and own messages replace their local echo.
     pub fn add_message(&self, cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<UserId> {
-        let unknown_users = self.print_msg(cache, msg, notify);
+        if self.get_message(&msg.id).is_some() {
+            return Vec::new();
+        }
+        if msg.is_own(cache) {
+            let index = self
+                .pending
+                .borrow()
+                .iter()
+                .position(|pending| msg.nonce.as_str() == Some(pending.nonce.as_str()));
+            if let Some(index) = index {
+                let pending = self.pending.borrow_mut().remove(index);
+                self.replace_pending(cache, &pending, msg);
+                return Vec::new();
+            }
+        }
+
+        let (unknown_users, _) = self.print_msg(cache, msg, notify);
         self.messages.borrow_mut().push(msg.clone());
+        if let Some(store) = self.store_for(msg.channel_id) {
+            store.store(&self.buffer.account(), msg);
+        }
+        unknown_users
+    }
+
+    /// Add messages that were missed while the connection was lost, `msgs` must be sorted oldest
+    /// first and `notify` tells which of them notify
+    ///
+    /// Messages received since the connection came back may already be printed, the missed
+    /// messages are then inserted before them and the buffer is redrawn.
+    pub fn add_missed_messages(
+        &self,
+        cache: &CacheRwLock,
+        msgs: &[Message],
+        notify: impl Fn(&Message) -> bool,
+    ) -> Vec<UserId> {
+        let first = match msgs.first() {
+            Some(msg) => msg.id,
+            None => return Vec::new(),
+        };
+        let newer_shown = self.messages.borrow().iter().any(|msg| msg.id > first);
+        if !newer_shown {
+            return msgs
+                .iter()
+                .flat_map(|msg| self.add_message(cache, msg, notify(msg)))
+                .collect();
+        }
+
+        {
+            let mut messages = self.messages.borrow_mut();
+            messages.extend(msgs.iter().cloned());
+            messages.sort_by_key(|msg| msg.id);
+        }
+        for msg in msgs {
+            if let Some(store) = self.store_for(msg.channel_id) {
+                store.store(&self.buffer.account(), msg);
+            }
+        }
+        let notifying = msgs
+            .iter()
+            .filter(|msg| notify(msg))
+            .map(|msg| msg.id)
+            .collect();
+        self.redraw_buffer_notifying(cache, &notifying)
+    }
+
+    /// Add older messages to the start of a buffer, `msgs` must be sorted oldest first
+    ///
+    /// The window showing the buffer is scrolled so the previously oldest message stays at the top
+    pub fn prepend_messages(&self, cache: &CacheRwLock, msgs: &[Message]) -> Vec<UserId> {
+        self.messages
+            .borrow_mut()
+            .splice(0..0, msgs.iter().cloned());
+        for msg in msgs {
+            if let Some(store) = self.store_for(msg.channel_id) {
+                store.store(&self.buffer.account(), msg);
+            }
+        }
+
+        // Weechat can only append lines, so the whole buffer has to be redrawn
+        self.buffer.clear();
+        let mut unknown_users = Vec::new();
+        let mut prepended_lines = 0;
+        let mut total_lines = 0;
+        for (index, message) in self.messages.borrow().iter().enumerate() {
+            let (users, lines) = self.print_msg(cache, &message, false);
+            if index < msgs.len() {
+                unknown_users.extend(users);
+                prepended_lines += lines;
+            }
+            total_lines += lines;
+        }
+
+        // Scroll the window of this buffer, which may not be the current one, to the top without
+        // using `/window scroll_top`, which would load more messages
+        let weechat = self.buffer.get_weechat();
+        if let Some(window) = window::buffer_window_number(&weechat, &self.buffer.get_name()) {
+            self.buffer.run_command(&format!(
+                "/window scroll -window {} -{}",
+                window, total_lines
+            ));
+            self.buffer.run_command(&format!(
+                "/window scroll -window {} +{}",
+                window, prepended_lines
+            ));
+        }
         unknown_users
     }
 
+    /// Print up to `count` of the most recent messages from the message store, returning the id
+    /// of the newest one
+    pub fn load_stored_history(&self, cache: &CacheRwLock, count: usize) -> Option<MessageId> {
+        let channel = self.buffer.channel_id()?;
+        let stored = self
+            .store_for(channel)?
+            .load_and_compact(&self.buffer.account(), channel);
+
+        for msg in &stored[stored.len().saturating_sub(count)..] {
+            self.print_msg(cache, msg, false);
+            self.messages.borrow_mut().push(msg.clone());
+        }
+        stored.last().map(|msg| msg.id)
+    }
+
     // Overwrite a previously printed message, has no effect if the message does not exist
     pub fn replace_message(
         &self,
@@ -67,44 +682,130 @@ impl MessageManager {
         id: &MessageId,
         msg: &Message,
     ) -> Vec<UserId> {
-        if let Some(old_msg) = self
+        let replaced = if let Some(old_msg) = self
             .messages
             .borrow_mut()
             .iter_mut()
             .find(|it| &it.id == id)
         {
+            // Reactions also cause a message to be replaced, only keep versions with new content
+            if old_msg.content != msg.content {
+                self.edits
+                    .borrow_mut()
+                    .entry(*id)
+                    .or_default()
+                    .push(old_msg.clone());
+            }
             *old_msg = msg.clone();
+            if let Some(store) = self.store_for(msg.channel_id) {
+                store.store(&self.buffer.account(), msg);
+            }
+            true
+        } else {
+            false
+        };
+        if replaced && !self.redraw_message(cache, msg) {
+            self.redraw_buffer(cache);
         }
-        // Using hdata to edit the line might be more efficient Would still use redrawing as a fall
-        // back in the event that the edit has a different amount of lines
-        self.redraw_buffer(cache);
         let (_, _, unknown_users) = formatting_utils::render_msg(
             cache,
             &self.buffer.get_weechat(),
+            &self.buffer.account(),
             msg,
             self.buffer.guild_id(),
+            false,
         );
         unknown_users
     }
 
     /// Delete a previously printed message, has no effect if the message does not exist
     pub fn delete_message(&self, cache: &CacheRwLock, id: &MessageId) -> Vec<UserId> {
-        let index = self.messages.borrow().iter().position(|it| &it.id == id);
-        let mut unknown_users = Vec::new();
-        if let Some(index) = index {
-            let msg = self.messages.borrow_mut().remove(index);
-            unknown_users = formatting_utils::render_msg(
-                cache,
-                &self.buffer.get_weechat(),
-                &msg,
-                self.buffer.guild_id(),
-            )
-            .2;
+        let shown = self.get_message(id).is_some();
+        let unknown_users = self.remove_message(cache, id);
+        if !shown {
+            return unknown_users;
+        }
+
+        let redrawn = match self.get_message(id) {
+            Some(msg) => self.redraw_message(cache, &msg),
+            // The message was removed because deleted messages are hidden
+            None => self.hide_message(id),
         };
-        // Performance, see comment above
+        if !redrawn {
+            self.redraw_buffer(cache);
+        }
+        unknown_users
+    }
+
+    /// Hide the lines of a printed message, they are tagged with `discord_hidden`
+    fn hide_message(&self, id: &MessageId) -> bool {
+        MessageLines::find(&self.buffer.get_weechat(), &self.buffer.get_name(), *id).hide()
+    }
+
+    /// Delete several previously printed messages at once, printing a summary of how many of them
+    /// were shown in this buffer
+    pub fn delete_messages(&self, cache: &CacheRwLock, ids: &[MessageId]) -> Vec<UserId> {
+        let shown = ids
+            .iter()
+            .filter(|id| self.get_message(id).is_some())
+            .count();
+        let unknown_users = ids
+            .iter()
+            .flat_map(|id| self.remove_message(cache, id))
+            .collect();
+        if shown == 0 {
+            return unknown_users;
+        }
+
+        let newest = self.messages.borrow().last().map(|msg| msg.id);
+        self.bulk_deletes.borrow_mut().push((newest, shown));
         self.redraw_buffer(cache);
         unknown_users
     }
+
+    fn print_bulk_delete(&self, count: usize) {
+        let weechat = self.buffer.get_weechat();
+        self.buffer.print_tags_dated(
+            0,
+            "notify_none,discord_deleted",
+            &format!(
+                "{}\t{} messages were deleted",
+                weechat.get_prefix("network"),
+                count
+            ),
+        );
+    }
+
+    /// Remove a message from the buffer and the store without redrawing, it is kept and marked as
+    /// deleted unless `weecord.look.deleted_messages` is `hide`
+    fn remove_message(&self, cache: &CacheRwLock, id: &MessageId) -> Vec<UserId> {
+        if let (Some(channel), Some(store)) = (self.buffer.channel_id(), &self.store) {
+            store.delete(&self.buffer.account(), channel, *id);
+        }
+        let index = self.messages.borrow().iter().position(|it| &it.id == id);
+        let index = match index {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+
+        let weechat = self.buffer.get_weechat();
+        let msg =
+            if crate::upgrade_plugin(&weechat).config.deleted_messages() == DeletedMessages::Hide {
+                self.messages.borrow_mut().remove(index)
+            } else {
+                self.deleted.borrow_mut().insert(*id);
+                self.messages.borrow()[index].clone()
+            };
+        formatting_utils::render_msg(
+            cache,
+            &weechat,
+            &self.buffer.account(),
+            &msg,
+            self.buffer.guild_id(),
+            false,
+        )
+        .2
+    }
 }
 
 impl Deref for MessageManager {
@@ -117,19 +818,25 @@ impl Deref for MessageManager {
 
 mod formatting_utils {
     use crate::{
-        discord::formatting,
+        config::DeletedMessages,
+        discord::{attachments, formatting},
         utils::{colorize_string, format_nick_color},
     };
+    use chrono::{DateTime, Local};
     use serenity::{
         cache::CacheRwLock,
         model::{
-            channel::{Channel, Message},
+            channel::{
+                Channel, Embed, EmbedField, Message, MessageReaction, MessageType, ReactionType,
+            },
             id::{GuildId, UserId},
         },
     };
-    use std::str::FromStr;
+    use std::{borrow::Cow, str::FromStr};
     use weechat::{ConfigOption, Weechat};
 
+    /// The tags of every line of a message, these identify the message, its author and channel so
+    /// that lines can be targeted by filters and triggers, or found again by message id
     pub fn msg_tags(cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<String> {
         let is_private = if let Some(channel) = msg.channel(cache) {
             if let Channel::Private(_) = channel {
@@ -143,37 +850,78 @@ mod formatting_utils {
 
         let self_mentioned = msg.mentions_user_id(cache.read().user.id);
 
-        let mut tags = Vec::new();
+        let mut tags = vec![
+            format!("discord_msg_{}", msg.id.0),
+            format!("discord_author_{}", msg.author.id.0),
+            format!("discord_channel_{}", msg.channel_id.0),
+            // Tags are comma separated, and weechat uses nick tags without spaces
+            format!(
+                "nick_{}",
+                msg.author.name.replace(|c| c == ',' || c == ' ', "_")
+            ),
+        ];
+        if msg.author.bot {
+            tags.push("discord_bot".to_owned());
+        }
+        if msg.webhook_id.is_some() {
+            tags.push("discord_webhook".to_owned());
+        }
+        if msg.kind != MessageType::Regular {
+            tags.push("discord_system".to_owned());
+        }
+        if is_action(msg) {
+            tags.push("irc_action".to_owned());
+        }
+        if msg.is_own(cache) {
+            tags.push("self_msg".to_owned());
+        }
         if notify {
             if self_mentioned {
-                tags.push("notify_highlight");
+                tags.push("notify_highlight".to_owned());
             } else if is_private {
-                tags.push("notify_private");
+                tags.push("notify_private".to_owned());
             } else {
-                tags.push("notify_message");
+                tags.push("notify_message".to_owned());
             };
         } else {
-            tags.push("notify_none");
+            tags.push("notify_none".to_owned());
         }
 
-        tags.into_iter().map(ToString::to_string).collect()
+        tags
+    }
+
+    /// Whether a message is a `/me` action, sent as a message that is entirely in italics
+    pub fn is_action(msg: &Message) -> bool {
+        msg.kind == MessageType::Regular && parsing::parse_action(&msg.content).is_some()
     }
 
     pub fn render_msg(
         cache: &CacheRwLock,
         weechat: &Weechat,
+        account: &str,
         msg: &Message,
         guild: Option<GuildId>,
+        show_spoilers: bool,
     ) -> (String, String, Vec<UserId>) {
+        // Channel and role mentions are rendered outside of code by `render_mentions`
         let opts = serenity::utils::ContentSafeOptions::new()
             .clean_here(false)
             .clean_everyone(false)
-            .clean_user(false);
+            .clean_user(false)
+            .clean_channel(false)
+            .clean_role(false);
 
         let mut msg_content = serenity::utils::content_safe(&cache, &msg.content, &opts);
         msg_content = crate::utils::clean_emojis(&msg_content);
         let unknown_users = clean_users(cache, &mut msg_content, true, guild);
 
+        // The italics of actions are replaced by the action prefix
+        let action = is_action(msg);
+        if action {
+            let text = msg_content.trim();
+            msg_content = text[1..text.len() - 1].to_owned();
+        }
+
         if msg.edited_timestamp.is_some() {
             let edited_text = weechat.color("8").into_owned()
                 + " (edited)"
@@ -181,65 +929,39 @@ mod formatting_utils {
             msg_content.push_str(&edited_text);
         }
 
+        let config = &crate::upgrade_plugin(weechat).config;
         for attachement in &msg.attachments {
             if !msg_content.is_empty() {
                 msg_content.push('\n');
             }
-            msg_content.push_str(&attachement.proxy_url);
-        }
-
-        for embed in &msg.embeds {
-            if !msg_content.is_empty() {
-                msg_content.push('\n');
+            msg_content.push_str(&attachments::describe(weechat, attachement));
+            if !config.image_previews.value() {
+                continue;
             }
-            if let Some(ref author) = embed.author {
-                msg_content.push('▎');
-                msg_content.push_str(&format!(
-                    "{}{}{}",
-                    weechat.color("bold"),
-                    author.name,
-                    weechat.color("reset"),
-                ));
-                if let Some(url) = &author.url {
-                    msg_content.push_str(&format!(" ({})", url));
+            if let Some(preview) = attachments::preview(
+                weechat,
+                attachement,
+                config.image_preview_width.value() as u32,
+                msg,
+                guild,
+                show_spoilers,
+            ) {
+                for line in preview {
+                    msg_content.push('\n');
+                    msg_content.push_str(&line);
                 }
-                msg_content.push('\n');
-            }
-            if let Some(ref title) = embed.title {
-                msg_content.push_str(
-                    &title
-                        .lines()
-                        .fold(String::new(), |acc, x| format!("{}▎{}\n", acc, x)),
-                );
-                msg_content.push('\n');
             }
-            if let Some(ref description) = embed.description {
-                msg_content.push_str(
-                    &description
-                        .lines()
-                        .fold(String::new(), |acc, x| format!("{}▎{}\n", acc, x)),
-                );
-                msg_content.push('\n');
-            }
-            for field in &embed.fields {
-                msg_content.push_str(&field.name);
-                msg_content.push_str(
-                    &field
-                        .value
-                        .lines()
-                        .fold(String::new(), |acc, x| format!("{}▎{}\n", acc, x)),
-                );
-                msg_content.push('\n');
+        }
+
+        let hide_link_previews = config.hide_link_previews.value();
+        for embed in &msg.embeds {
+            if hide_link_previews && is_link_preview(embed, &msg.content) {
+                continue;
             }
-            if let Some(ref footer) = embed.footer {
-                msg_content.push_str(
-                    &footer
-                        .text
-                        .lines()
-                        .fold(String::new(), |acc, x| format!("{}▎{}\n", acc, x)),
-                );
+            if !msg_content.is_empty() {
                 msg_content.push('\n');
             }
+            msg_content.push_str(&render_embed(weechat, embed));
         }
 
         let mut prefix = String::new();
@@ -269,11 +991,23 @@ mod formatting_utils {
 
         use serenity::model::channel::MessageType::*;
         if let Regular = msg.kind {
-            (
-                prefix,
-                formatting::discord_to_weechat(weechat, &msg_content),
-                unknown_users,
-            )
+            let mentions =
+                |text: &str| formatting::render_mentions(cache, weechat, account, guild, text);
+            let mut content =
+                formatting::discord_to_weechat(weechat, &msg_content, show_spoilers, &mentions);
+            let prefix = if action {
+                content = format!("{} {}", author, content);
+                weechat.get_prefix("action").into_owned()
+            } else {
+                prefix
+            };
+            if !msg.reactions.is_empty() {
+                if !content.is_empty() {
+                    content.push('\n');
+                }
+                content.push_str(&render_reactions(weechat, &msg.reactions));
+            }
+            (prefix, content, unknown_users)
         } else {
             let (prefix, body) = match msg.kind {
                 GroupRecipientAddition | MemberJoin => {
@@ -312,6 +1046,335 @@ mod formatting_utils {
         }
     }
 
+    /// Whether an embed is a preview of a link that is already in the message
+    fn is_link_preview(embed: &Embed, content: &str) -> bool {
+        // Embeds sent by bots and webhooks are "rich", everything else is generated from a link
+        embed.kind != "rich"
+            && embed
+                .url
+                .as_ref()
+                .map_or(false, |url| content.contains(url.as_str()))
+    }
+
+    /// Render an embed, with a sidebar in the color of the embed
+    fn render_embed(weechat: &Weechat, embed: &Embed) -> String {
+        let mut lines = Vec::new();
+
+        if let Some(author) = &embed.author {
+            let mut line = format!(
+                "{}{}{}",
+                weechat.color("bold"),
+                author.name,
+                weechat.color("-bold")
+            );
+            if let Some(url) = &author.url {
+                line.push_str(&format!(" ({})", url));
+            }
+            lines.push(line);
+        }
+        match (&embed.title, &embed.url) {
+            (Some(title), url) => {
+                let mut line = format!(
+                    "{}{}{}",
+                    weechat.color("bold"),
+                    title,
+                    weechat.color("-bold")
+                );
+                if let Some(url) = url {
+                    line.push_str(&format!(
+                        " {}<{}>{}",
+                        weechat.color("8"),
+                        url,
+                        weechat.color("reset")
+                    ));
+                }
+                lines.push(line);
+            },
+            (None, Some(url)) => lines.push(url.clone()),
+            (None, None) => {},
+        }
+        if let Some(description) = &embed.description {
+            lines.extend(description.lines().map(str::to_owned));
+        }
+
+        // Consecutive inline fields are shown side by side, up to three on a row like in the
+        // Discord client
+        let mut fields = embed.fields.iter().peekable();
+        while let Some(field) = fields.next() {
+            let mut row = vec![field];
+            while field.inline && row.len() < 3 {
+                match fields.peek() {
+                    Some(next) if next.inline => row.push(fields.next().unwrap()),
+                    _ => break,
+                }
+            }
+            lines.extend(render_field_row(weechat, &row));
+        }
+
+        if let Some(image) = &embed.image {
+            lines.push(format!("image: {}", image.url));
+        }
+        if let Some(thumbnail) = &embed.thumbnail {
+            lines.push(format!("thumbnail: {}", thumbnail.url));
+        }
+
+        let timestamp = embed
+            .timestamp
+            .as_ref()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| {
+                time.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            });
+        let footer = embed.footer.as_ref().map(|footer| footer.text.clone());
+        let footer = match (footer, timestamp) {
+            (Some(footer), Some(timestamp)) => Some(format!("{} • {}", footer, timestamp)),
+            (footer, timestamp) => footer.or(timestamp),
+        };
+        if let Some(footer) = footer {
+            lines.push(format!(
+                "{}{}{}",
+                weechat.color("8"),
+                footer,
+                weechat.color("reset")
+            ));
+        }
+
+        let sidebar = if embed.colour.0 == 0 {
+            "▎".to_owned()
+        } else {
+            format!(
+                "{}▎{}",
+                weechat.color(&crate::utils::rgb_to_ansi(embed.colour).to_string()),
+                weechat.color("reset")
+            )
+        };
+        lines
+            .iter()
+            .map(|line| format!("{}{}", sidebar, line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Render fields as columns, with the names in bold above their values
+    fn render_field_row(weechat: &Weechat, fields: &[&EmbedField]) -> Vec<String> {
+        let columns: Vec<Vec<&str>> = fields
+            .iter()
+            .map(|field| {
+                Some(field.name.as_str())
+                    .into_iter()
+                    .chain(field.value.lines())
+                    .collect()
+            })
+            .collect();
+        let widths: Vec<usize> = columns
+            .iter()
+            .map(|column| {
+                column
+                    .iter()
+                    .map(|cell| cell.chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let height = columns.iter().map(Vec::len).max().unwrap_or(0);
+
+        (0..height)
+            .map(|row| {
+                let cells: Vec<_> = columns
+                    .iter()
+                    .zip(&widths)
+                    .map(|(column, width)| {
+                        let cell = column.get(row).copied().unwrap_or_default();
+                        let padding = " ".repeat(width - cell.chars().count());
+                        if row == 0 {
+                            format!(
+                                "{}{}{}{}",
+                                weechat.color("bold"),
+                                cell,
+                                weechat.color("-bold"),
+                                padding
+                            )
+                        } else {
+                            format!("{}{}", cell, padding)
+                        }
+                    })
+                    .collect();
+                cells.join("   ").trim_end().to_owned()
+            })
+            .collect()
+    }
+
+    /// Render the reactions of a message on a single line, highlighting the ones made by the
+    /// current user
+    fn render_reactions(weechat: &Weechat, reactions: &[MessageReaction]) -> String {
+        reactions
+            .iter()
+            .map(|reaction| {
+                let emoji = match &reaction.reaction_type {
+                    ReactionType::Custom {
+                        name: Some(name), ..
+                    } => format!(":{}:", name),
+                    ReactionType::Custom { id, .. } => format!(":{}:", id),
+                    ReactionType::Unicode(emoji) => emoji.clone(),
+                    // Reaction types added to Discord later than serenity
+                    ReactionType::__Nonexhaustive => "?".to_owned(),
+                };
+                let color = if reaction.me { "cyan" } else { "8" };
+                format!(
+                    "{}[{}{} {}{}]{}",
+                    weechat.color(color),
+                    weechat.color("reset"),
+                    emoji,
+                    reaction.count,
+                    weechat.color(color),
+                    weechat.color("reset"),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Render the words that changed between two versions of a message on a single line
+    pub fn render_edit_diff(
+        weechat: &Weechat,
+        old: &str,
+        new: &str,
+        show_spoilers: bool,
+    ) -> String {
+        let (old, new) = if show_spoilers {
+            (Cow::from(old), Cow::from(new))
+        } else {
+            (
+                formatting::mask_spoilers(weechat, old),
+                formatting::mask_spoilers(weechat, new),
+            )
+        };
+        let old: Vec<_> = old.split_whitespace().collect();
+        let new: Vec<_> = new.split_whitespace().collect();
+
+        let common_start = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let common_end = old[common_start..]
+            .iter()
+            .rev()
+            .zip(new[common_start..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let removed = &old[common_start..old.len() - common_end];
+        let added = &new[common_start..new.len() - common_end];
+
+        let mut diff = format!("{}edited:{}", weechat.color("8"), weechat.color("reset"));
+        if common_start > 0 {
+            diff.push_str(" …");
+        }
+        if !removed.is_empty() {
+            diff.push_str(&format!(
+                " {}-{}{}",
+                weechat.color("red"),
+                removed.join(" "),
+                weechat.color("reset")
+            ));
+        }
+        if !added.is_empty() {
+            diff.push_str(&format!(
+                " {}+{}{}",
+                weechat.color("green"),
+                added.join(" "),
+                weechat.color("reset")
+            ));
+        }
+        if common_end > 0 {
+            diff.push_str(" …");
+        }
+        diff
+    }
+
+    /// Render a deleted message, either crossed out or replaced by a placeholder
+    pub fn render_deleted(
+        cache: &CacheRwLock,
+        weechat: &Weechat,
+        msg: &Message,
+        guild: Option<GuildId>,
+        mode: DeletedMessages,
+        show_spoilers: bool,
+    ) -> String {
+        let content = match mode {
+            DeletedMessages::Strike => {
+                let mut content = crate::utils::clean_emojis(&msg.content);
+                clean_users(cache, &mut content, false, guild);
+                if !show_spoilers {
+                    content = formatting::mask_spoilers(weechat, &content).into_owned();
+                }
+                // Weechat has no strike-through attribute, so use a combining overlay instead
+                content
+                    .chars()
+                    .flat_map(|c| {
+                        if c.is_whitespace() {
+                            vec![c]
+                        } else {
+                            vec![c, '\u{0336}']
+                        }
+                    })
+                    .collect::<String>()
+                    + " (deleted)"
+            },
+            DeletedMessages::Placeholder | DeletedMessages::Hide => "(message deleted)".to_owned(),
+        };
+        format!(
+            "{}{}{}",
+            weechat.color("red"),
+            content,
+            weechat.color("reset")
+        )
+    }
+
+    /// Render the quote of a reply to a message that has not been loaded into the buffer
+    pub fn render_unknown_reply_quote(weechat: &Weechat) -> String {
+        format!(
+            "{}↪ reply to an earlier message{}",
+            weechat.color("8"),
+            weechat.color("reset"),
+        )
+    }
+
+    /// Render a dimmed, single line quote of a message that is being replied to
+    pub fn render_reply_quote(
+        cache: &CacheRwLock,
+        weechat: &Weechat,
+        referenced: &Message,
+        guild: Option<GuildId>,
+        show_spoilers: bool,
+    ) -> String {
+        const MAX_QUOTE_LEN: usize = 60;
+
+        let mut content = crate::utils::clean_emojis(&referenced.content);
+        clean_users(cache, &mut content, false, guild);
+        let content = if show_spoilers {
+            Cow::from(content.as_str())
+        } else {
+            formatting::mask_spoilers(weechat, &content)
+        };
+        let mut snippet: String = content
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .take(MAX_QUOTE_LEN)
+            .collect();
+        if content.chars().count() > snippet.chars().count() {
+            snippet.push('…');
+        }
+
+        format!(
+            "{}↪ @{}: {}{}",
+            weechat.color("8"),
+            author_display_name(cache, referenced, guild),
+            snippet,
+            weechat.color("reset"),
+        )
+    }
+
     pub fn author_display_name(
         cache: &CacheRwLock,
         msg: &Message,
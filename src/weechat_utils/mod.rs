@@ -1,4 +1,14 @@
 mod buffer_manager;
 pub use buffer_manager::BufferManager;
+pub mod config_section;
+mod hdata;
 mod message_manager;
 pub use message_manager::MessageManager;
+pub mod signal;
+mod window;
+
+use std::ffi::CString;
+
+fn cstr(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
@@ -0,0 +1,21 @@
+//! Send weechat signals, which rust-weechat does not wrap
+
+use super::cstr;
+use std::os::raw::c_void;
+use weechat::Weechat;
+
+/// Send `signal` with a string argument, so scripts and triggers can react to it
+pub fn send_string(weechat: &Weechat, signal: &str, data: &str) {
+    let plugin = weechat.as_ptr();
+    let signal = cstr(signal);
+    let data = cstr(data);
+
+    unsafe {
+        let signal_send = (*plugin).hook_signal_send.unwrap();
+        signal_send(
+            signal.as_ptr(),
+            cstr("string").as_ptr(),
+            data.as_ptr() as *mut c_void,
+        );
+    }
+}
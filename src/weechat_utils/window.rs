@@ -0,0 +1,26 @@
+//! Find the windows buffers are displayed in, which rust-weechat does not wrap
+
+use super::cstr;
+use weechat::Weechat;
+
+/// The number of a window that displays a weecord buffer, if it is displayed
+pub fn buffer_window_number(weechat: &Weechat, buffer_name: &str) -> Option<i32> {
+    let plugin = weechat.as_ptr();
+
+    unsafe {
+        let buffer_search = (*plugin).buffer_search.unwrap();
+        let buffer = buffer_search(cstr("weecord").as_ptr(), cstr(buffer_name).as_ptr());
+        if buffer.is_null() {
+            return None;
+        }
+
+        let window_search_with_buffer = (*plugin).window_search_with_buffer.unwrap();
+        let window = window_search_with_buffer(buffer);
+        if window.is_null() {
+            return None;
+        }
+
+        let window_get_integer = (*plugin).window_get_integer.unwrap();
+        Some(window_get_integer(window, cstr("number").as_ptr()))
+    }
+}